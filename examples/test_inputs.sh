@@ -0,0 +1,35 @@
+#!/bin/bash
+# Check that compressed and piped reads give the same classifications as the
+# plain FASTA. Requires gzip, bzip2, xz and zstd.
+set -euo pipefail
+
+KLASSIFY=${KLASSIFY:-klassify}
+cd "$(dirname "$0")"
+WORK=$(mktemp -d)
+trap 'rm -rf "$WORK"' EXIT
+
+$KLASSIFY build parents.genome.fa --by-record -o "$WORK/kmers.bc"
+cp f1_reads.fa "$WORK/"
+cd "$WORK"
+gzip -c f1_reads.fa > gz.fa.gz
+bzip2 -c f1_reads.fa > bz2.fa.bz2
+xz -c f1_reads.fa > xz.fa.xz
+zstd -q -c f1_reads.fa > zst.fa.zst
+
+$KLASSIFY classify kmers.bc f1_reads.fa -o plain
+expected=plain/f1_reads.fa.read_classifications.tsv
+for reads in gz.fa.gz bz2.fa.bz2 xz.fa.xz zst.fa.zst; do
+    $KLASSIFY classify kmers.bc "$reads" -o "$reads.out"
+    cmp "$expected" "$reads.out/$reads.read_classifications.tsv"
+done
+zstd -q -dc zst.fa.zst | $KLASSIFY classify kmers.bc - -o stdin
+cmp "$expected" stdin/stdin.read_classifications.tsv
+
+# Extract and breakpoint also read from stdin
+$KLASSIFY extract plain.filtered.tsv f1_reads.fa -o plain.fa
+gzip -dc gz.fa.gz | $KLASSIFY extract plain.filtered.tsv - -o stdin.fa
+cmp plain.fa stdin.fa
+$KLASSIFY breakpoint kmers.bc f1_reads.fa
+bzip2 -dc bz2.fa.bz2 | $KLASSIFY breakpoint kmers.bc -
+cmp f1_reads.fa.classifications.bed stdin.classifications.bed
+echo "All inputs give the same results"
@@ -1,12 +1,14 @@
-use crate::info::{load_kmer_db, map_kmer_to_file};
-use crate::models::{prefix, prefix_until_dot, SingletonKmers};
+use crate::db::{kmer_size_of, load_kmer_index};
+use crate::index::KmerIndex;
+use crate::kmer::{is_wide, Kmer};
+use crate::models::{assert_stdin_once, open_fastx, prefix, prefix_until_dot, SingletonKmers};
+use crate::sampling::for_each_sampled_kmer;
 
 use clap::Parser;
 use log;
-use needletail::{parse_fastx_file, Sequence};
+use needletail::Sequence;
 use rayon::prelude::*;
 use std::{
-    collections::HashMap,
     fs::File,
     io::{BufWriter, Write},
 };
@@ -20,22 +22,30 @@ pub struct BreakpointArgs {
 }
 
 /// Generate the kmer BED file for multiple FASTA files
-pub fn breakpoint(bincode_file: &str, fasta_files: &Vec<String>) {
-    let singleton_kmers = load_kmer_db(bincode_file);
-    let kmer_to_file = map_kmer_to_file(&singleton_kmers);
+pub fn breakpoint(bincode_file: &str, fasta_files: &[String]) {
+    if is_wide(kmer_size_of(bincode_file)) {
+        breakpoint_with::<u128>(bincode_file, fasta_files);
+    } else {
+        breakpoint_with::<u64>(bincode_file, fasta_files);
+    }
+}
+
+fn breakpoint_with<K: Kmer>(bincode_file: &str, fasta_files: &[String]) {
+    assert_stdin_once(fasta_files);
+    let (singleton_kmers, kmer_to_file, _) = load_kmer_index::<K>(bincode_file, true);
     fasta_files.par_iter().for_each(|fasta_file| {
         breakpoint_one(&singleton_kmers, &kmer_to_file, fasta_file);
     });
 }
 
 /// Generate the kmer BED file for one FASTA file
-fn breakpoint_one(
-    singleton_kmers: &SingletonKmers,
-    kmer_to_file: &HashMap<u64, usize>,
+fn breakpoint_one<K: Kmer>(
+    singleton_kmers: &SingletonKmers<K>,
+    kmer_to_file: &KmerIndex<K>,
     fasta_file: &str,
 ) {
     // Classify the reads
-    let mut reader = parse_fastx_file(fasta_file).expect("valid FASTA file");
+    let mut reader = open_fastx(fasta_file).expect("valid FASTA file");
     let file_prefix = prefix(fasta_file);
     let output_file = file_prefix.to_string() + ".classifications.bed";
     let mut writer = BufWriter::new(File::create(&output_file).unwrap());
@@ -43,6 +53,7 @@ fn breakpoint_one(
 
     // Iterate through the reads
     let kmer_size = singleton_kmers.kmer_size;
+    let sampling = singleton_kmers.sampling;
     while let Some(record) = reader.next() {
         let record = record.expect("valid record");
         let seq = record.normalize(false);
@@ -53,19 +64,19 @@ fn breakpoint_one(
             .next()
             .unwrap()
             .to_string();
-        for (pos, kmer, _) in seq.bit_kmers(kmer_size, true) {
-            if let Some(&file_index) = kmer_to_file.get(&kmer.0) {
+        for_each_sampled_kmer(&seq, kmer_size, sampling, |pos, kmer: K| {
+            if let Some(file_index) = kmer_to_file.get(kmer) {
                 let to_write: String = format!(
                     "{}\t{}\t{}\t{}:{}",
                     id,
                     pos,
                     pos + kmer_size as usize,
                     prefix_until_dot(&singleton_kmers.fasta_files[file_index]),
-                    kmer.0
+                    kmer
                 );
                 writeln!(writer, "{}", to_write).unwrap();
             }
-        }
+        });
     }
     log::info!("Classifications written to `{}`", output_file);
 }
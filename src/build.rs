@@ -1,16 +1,30 @@
-use crate::models::{prefix_until_dot, SingletonKmers};
-use bincode::serialize_into;
+use crate::db::{
+    kmer_size_of, load_kmer_db, read_shared_kmers, shared_kmers_file, write_kmer_db,
+    write_shared_kmers,
+};
+use crate::index::is_kmer_index;
+use crate::index::{write_kmer_index, KmerIndex};
+use crate::kmer::{is_wide, validate_kmer_size, Kmer};
+use crate::models::{
+    open_fastx, prefix, prefix_until_dot, DbHeader, GroupLevel, SharedKmers, SingletonKmers,
+};
+use crate::sampling::{for_each_sampled_kmer, Sampling};
 use clap::Parser;
-use needletail::{parse_fastx_file, Sequence};
+use csv::ReaderBuilder;
+use needletail::Sequence;
 use rayon::prelude::*;
-use std::{
-    collections::{HashMap, HashSet},
-    fs::File,
-    io::BufWriter,
-};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 /// K-mer length
 const KMER_SIZE: u8 = 24;
+/// Min read support of a singleton kmer, when reads are given
+const MIN_SUPPORT: u32 = 2;
 /// File name for singleton kmers
 const SINGLETON_KMERS: &str = "kmers.bc";
 
@@ -22,72 +36,1727 @@ pub struct BuildArgs {
     #[clap(short, long, default_value = SINGLETON_KMERS)]
     pub output_file: String,
     /// K-mer size
-    #[clap(short, long, default_value_t = KMER_SIZE)]
+    #[clap(short, long, default_value_t = KMER_SIZE, conflicts_with_all = ["add", "remove"])]
     pub kmer_size: u8,
+    /// Write a memory-mapped database that is queried without deserialization
+    #[clap(long, default_value_t = false)]
+    pub mmap: bool,
+    /// Memory budget in GB, sorts kmers on disk next to the output file
+    #[clap(long, conflicts_with_all = ["add", "remove"])]
+    pub max_memory: Option<f64>,
+    /// Use FASTA records as classes instead of input files, e.g. one multi-FASTA
+    #[clap(long, default_value_t = false)]
+    pub by_record: bool,
+    /// TSV mapping record names to class names (implies --by-record)
+    #[clap(long)]
+    pub class_map: Option<String>,
+    /// TSV assigning classes to groups, with a header row naming the levels,
+    /// to also find kmers unique to each group, e.g. homologs of a chromosome
+    #[clap(long)]
+    pub groups: Option<String>,
+    /// Max occurrences of a kmer within its class, to discard repetitive kmers
+    /// (1 keeps only kmers that occur exactly once); group kmers are discarded
+    /// when repeated in any of their classes
+    #[clap(long)]
+    pub max_occurrences: Option<u32>,
+    /// Parental reads to count kmer support in, keeping only singleton and
+    /// group kmers whose read support is within --min-support and --max-support
+    #[clap(long, num_args = 1..)]
+    pub reads: Vec<String>,
+    /// Min occurrences of a singleton kmer in the reads, to discard assembly errors
+    #[clap(long, default_value_t = MIN_SUPPORT)]
+    pub min_support: u32,
+    /// Max occurrences of a singleton kmer in the reads, to discard repeats
+    #[clap(long)]
+    pub max_support: Option<u32>,
+    /// Keep only minimizers of windows of this many kmers, for faster lookups
+    #[clap(long, conflicts_with = "syncmer")]
+    pub minimizer: Option<u8>,
+    /// Keep only open syncmers with this s-mer size, for faster lookups
+    #[clap(long)]
+    pub syncmer: Option<u8>,
+    /// Also write the kmers shared between classes, so that classes can later be
+    /// added or removed without a full rebuild
+    #[clap(long, default_value_t = false)]
+    pub keep_shared: bool,
+    /// Add the input FASTA files as new classes to the existing output database
+    #[clap(long, default_value_t = false)]
+    pub add: bool,
+    /// Remove these classes from the existing output database
+    #[clap(long, num_args = 1..)]
+    pub remove: Vec<String>,
+    /// Write the number of kmers shared by each pair of classes to this TSV
+    #[clap(long)]
+    pub shared_matrix: Option<String>,
 }
 
 /// Convert FASTA files to singleton k-mers
-pub fn build(fasta_files: &Vec<String>, output_file: &str, kmer_size: u8) {
-    let all_sets = fasta_files
-        .par_iter()
-        .map(|fasta_file| get_kmers(fasta_file, kmer_size))
+pub fn build(args: &BuildArgs) {
+    // Updates keep the kmer size of the existing database
+    let kmer_size = if args.add || !args.remove.is_empty() {
+        kmer_size_of(&args.output_file)
+    } else {
+        args.kmer_size
+    };
+    validate_kmer_size(kmer_size);
+    if is_wide(kmer_size) {
+        build_with::<u128>(args, kmer_size);
+    } else {
+        build_with::<u64>(args, kmer_size);
+    }
+}
+
+fn build_with<K: Kmer>(args: &BuildArgs, kmer_size: u8) {
+    let fasta_files = &args.fasta_files;
+    let output_file = &args.output_file;
+    let update = args.add || !args.remove.is_empty();
+    let mut classes = ClassNames::new(args.class_map.as_deref());
+    let mut base = Base::default();
+    if update {
+        assert!(
+            args.add != fasta_files.is_empty(),
+            "pass FASTA files along with --add to add them to `{}`",
+            output_file
+        );
+        assert!(
+            args.minimizer.is_none()
+                && args.syncmer.is_none()
+                && args.reads.is_empty()
+                && args.max_occurrences.is_none(),
+            "sampling, read support and repeat filters are not supported when updating"
+        );
+        let names = base.load(
+            output_file,
+            |name| !args.remove.iter().any(|x| x == name),
+            &mut classes,
+            true,
+        );
+        for name in args.remove.iter() {
+            assert!(
+                names.contains(name),
+                "class `{}` is not in `{}`",
+                name,
+                output_file
+            );
+        }
+    }
+    let by_record = args.by_record || args.class_map.is_some() || base.by_record;
+    let keep_shared = args.keep_shared || update;
+    let mmap = args.mmap || (update && is_kmer_index(output_file));
+    let sampling = match (args.minimizer, args.syncmer) {
+        (Some(w), _) => Sampling::Minimizer { w },
+        (_, Some(s)) => Sampling::Syncmer { s },
+        _ => Sampling::All,
+    };
+    sampling.validate(kmer_size);
+    if !by_record {
+        // Files sharing the name before the first dot form one class
+        for fasta_file in fasta_files.iter() {
+            let name = prefix_until_dot(fasta_file);
+            let n = classes.n();
+            let class = classes.add(&name);
+            assert!(
+                class == n || !update,
+                "class `{}` is already in `{}`",
+                name,
+                output_file
+            );
+            if class < n {
+                log::info!("`{}` is added to class `{}`", fasta_file, name);
+            }
+        }
+    }
+    // Updates keep the groups of the existing database unless given
+    let groups = match args.groups.as_deref() {
+        Some(groups_file) => Some(Groups::load(groups_file)),
+        None => base.groups.take(),
+    };
+    // Identify all the kmers that appear once and only once in all the classes
+    let mut singletons = match args.max_memory {
+        Some(max_memory) => {
+            // Next to the output, and removed when dropped, also if the build fails
+            let output_dir = Path::new(output_file)
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            let tmp_dir = tempfile::Builder::new()
+                .prefix(&format!("{}.", prefix(output_file)))
+                .suffix(".tmp")
+                .tempdir_in(output_dir)
+                .expect("valid temporary directory");
+            let run_files = sort_on_disk::<K>(
+                fasta_files,
+                kmer_size,
+                &mut classes,
+                by_record,
+                max_memory,
+                tmp_dir.path().to_str().unwrap(),
+            );
+            let levels = group_levels(groups.as_ref(), &classes.names);
+            let init = Singletons::new(classes.n(), &levels, args.max_occurrences, keep_shared)
+                .count_shared(args.shared_matrix.is_some());
+            merge_on_disk(&run_files, &init)
+        }
+        None => {
+            let mut all_kmers = collect_in_memory::<K>(
+                fasta_files,
+                kmer_size,
+                &mut classes,
+                by_record,
+                args.max_occurrences.is_some(),
+            );
+            if update {
+                let base_kmers = base.take_class_kmers(&classes.names);
+                all_kmers = add_base(base_kmers, all_kmers, &classes.names);
+            }
+            let levels = group_levels(groups.as_ref(), &classes.names);
+            let init = Singletons::new(classes.n(), &levels, args.max_occurrences, keep_shared)
+                .count_shared(args.shared_matrix.is_some());
+            merge_in_memory(all_kmers, kmer_size, &init)
+        }
+    };
+    if sampling != Sampling::All {
+        let (names, kmers) = singletons.take_kmers(&classes.names);
+        let kmers = filter_by_sampling(kmers, kmer_size, &names, fasta_files, sampling);
+        singletons.restore_kmers(kmers);
+    }
+    if !args.reads.is_empty() {
+        let (names, kmers) = singletons.take_kmers(&classes.names);
+        let kmers = filter_by_support(
+            kmers,
+            kmer_size,
+            &names,
+            &args.reads,
+            args.min_support,
+            args.max_support,
+        );
+        singletons.restore_kmers(kmers);
+    }
+    singletons.log(&classes.names);
+    if let Some(shared_matrix) = args.shared_matrix.as_deref() {
+        let n = classes.n();
+        let shared_counts = singletons
+            .shared_counts
+            .take()
+            .unwrap_or_else(|| vec![vec![0; n]; n]);
+        write_shared_matrix(shared_matrix, &classes.names, &shared_counts);
+    }
+    let all_fasta_files = base
+        .fasta_files
+        .iter()
+        .chain(fasta_files.iter())
+        .cloned()
         .collect::<Vec<_>>();
-    // Identify all the kmers that appear once and only once in all the files
-    let mut kmer_counts = HashMap::new();
-    for kmer_set in all_sets.iter() {
-        for kmer in kmer_set.iter() {
-            *kmer_counts.entry(*kmer).or_insert(0) += 1;
+    let mut params = vec![
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("kmer_size".to_string(), kmer_size.to_string()),
+        ("fasta_files".to_string(), all_fasta_files.join(",")),
+        ("total_kmers".to_string(), singletons.total.to_string()),
+    ];
+    if let Some(max_memory) = args.max_memory {
+        params.push(("max_memory".to_string(), max_memory.to_string()));
+    }
+    if by_record {
+        params.push(("by_record".to_string(), "true".to_string()));
+    }
+    if let Some(class_map) = &args.class_map {
+        params.push(("class_map".to_string(), class_map.clone()));
+    }
+    if let Some(groups) = &groups {
+        params.push(("groups".to_string(), groups.source.clone()));
+    }
+    if let Some(max_occurrences) = args.max_occurrences {
+        params.push(("max_occurrences".to_string(), max_occurrences.to_string()));
+    }
+    if sampling != Sampling::All {
+        params.push(("sampling".to_string(), sampling.to_string()));
+    }
+    if !args.reads.is_empty() {
+        params.push(("reads".to_string(), args.reads.join(",")));
+        params.push(("min_support".to_string(), args.min_support.to_string()));
+        if let Some(max_support) = args.max_support {
+            params.push(("max_support".to_string(), max_support.to_string()));
         }
     }
-    log::info!("Total unique kmers: {}", kmer_counts.len());
-    let singleton_kmers = kmer_counts
-        .into_iter()
-        .filter(|(_, count)| *count == 1)
-        .map(|(kmer, _)| kmer)
-        .collect::<HashSet<_>>();
-    log::info!("Singleton kmers: {}", singleton_kmers.len());
-    // Find the unique kmers in each file
-    let singletons = (fasta_files, all_sets)
-        .into_par_iter()
-        .map(|(fasta_file, kmer_set)| {
-            let singleton_kmers_per_file = kmer_set
-                .intersection(&singleton_kmers)
+    write_singletons(
+        output_file,
+        kmer_size,
+        classes.names,
+        singletons,
+        sampling,
+        params,
+        mmap,
+    );
+}
+
+/// Recompute the singleton kmers of the classes passing `keep` in existing
+/// databases, as if they were built together, and write them to `output_file`.
+/// Kmers shared between the classes of a database are only known from its
+/// shared kmers; without them, only its singleton kmers are considered.
+pub fn rebuild(
+    bincode_files: &[String],
+    keep: impl Fn(&str) -> bool,
+    output_file: &str,
+    mmap: bool,
+    keep_shared: bool,
+    require_shared: bool,
+    extra_params: Vec<(String, String)>,
+) {
+    let kmer_size = kmer_size_of(&bincode_files[0]);
+    for bincode_file in bincode_files.iter() {
+        let other_kmer_size = kmer_size_of(bincode_file);
+        assert!(
+            other_kmer_size == kmer_size,
+            "`{}` has kmer size {}, unlike `{}` ({})",
+            bincode_file,
+            other_kmer_size,
+            bincode_files[0],
+            kmer_size
+        );
+    }
+    let options = (mmap, keep_shared, require_shared);
+    if is_wide(kmer_size) {
+        rebuild_with::<u128>(
+            bincode_files,
+            keep,
+            output_file,
+            kmer_size,
+            options,
+            extra_params,
+        );
+    } else {
+        rebuild_with::<u64>(
+            bincode_files,
+            keep,
+            output_file,
+            kmer_size,
+            options,
+            extra_params,
+        );
+    }
+}
+
+fn rebuild_with<K: Kmer>(
+    bincode_files: &[String],
+    keep: impl Fn(&str) -> bool,
+    output_file: &str,
+    kmer_size: u8,
+    (mmap, keep_shared, require_shared): (bool, bool, bool),
+    extra_params: Vec<(String, String)>,
+) {
+    let mut classes = ClassNames::new(None);
+    let mut base = Base::<K>::default();
+    for bincode_file in bincode_files.iter() {
+        base.load(bincode_file, &keep, &mut classes, require_shared);
+    }
+    assert!(classes.n() > 0, "no classes selected");
+    let all_kmers = base.take_class_kmers(&classes.names);
+    let levels = group_levels(base.groups.as_ref(), &classes.names);
+    let init = Singletons::new(classes.n(), &levels, None, keep_shared);
+    let singletons = merge_in_memory(all_kmers, kmer_size, &init);
+    singletons.log(&classes.names);
+    let mut params = vec![
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("kmer_size".to_string(), kmer_size.to_string()),
+        ("fasta_files".to_string(), base.fasta_files.join(",")),
+    ];
+    // The kmers shared with the other classes are needed to count all the kmers
+    if !base.singletons_only {
+        params.push(("total_kmers".to_string(), singletons.total.to_string()));
+    }
+    if base.by_record {
+        params.push(("by_record".to_string(), "true".to_string()));
+    }
+    if let Some(groups) = &base.groups {
+        params.push(("groups".to_string(), groups.source.clone()));
+    }
+    if base.sampling != Sampling::All {
+        params.push(("sampling".to_string(), base.sampling.to_string()));
+    }
+    params.extend(base.filter_params);
+    params.extend(extra_params);
+    write_singletons(
+        output_file,
+        kmer_size,
+        classes.names,
+        singletons,
+        base.sampling,
+        params,
+        mmap,
+    );
+}
+
+/// Write the singleton kmers, and the shared kmers if they were kept
+fn write_singletons<K: Kmer>(
+    output_file: &str,
+    kmer_size: u8,
+    class_names: Vec<String>,
+    singletons: Singletons<K>,
+    sampling: Sampling,
+    params: Vec<(String, String)>,
+    mmap: bool,
+) {
+    let header = DbHeader {
+        kmer_size,
+        canonical: true,
+        params,
+        ..Default::default()
+    };
+    if singletons.keep_shared {
+        let (kmers, classes) = singletons.shared.into_iter().unzip();
+        let shared_file = shared_kmers_file(output_file);
+        write_shared_kmers(
+            &shared_file,
+            &SharedKmers {
+                fasta_files: class_names.clone(),
+                kmers,
+                classes,
+            },
+        );
+        log::info!("Shared kmers written to `{}`", shared_file);
+    }
+    // Serialize the singleton kmers to a file
+    let mut singleton_kmers = SingletonKmers {
+        kmer_size,
+        fasta_files: class_names,
+        kmers: singletons.kmers,
+        groups: singletons.levels,
+        sampling,
+        header,
+    };
+    if mmap {
+        let kmers = std::mem::take(&mut singleton_kmers.kmers);
+        let kmer_to_file = KmerIndex::new(kmer_size, kmers);
+        write_kmer_index(output_file, &singleton_kmers, &kmer_to_file);
+    } else {
+        write_kmer_db(output_file, &singleton_kmers);
+    }
+    log::info!("Singleton kmers written to `{}`", output_file);
+}
+
+/// Classes loaded from existing databases, to add or remove classes, or to
+/// subset and merge databases
+#[derive(Default)]
+struct Base<K> {
+    /// All the kmers of each class, singleton or shared
+    kmers: Vec<Vec<K>>,
+    /// FASTA files the loaded classes were built from
+    fasta_files: Vec<String>,
+    by_record: bool,
+    sampling: Sampling,
+    /// Groups of the loaded classes, if the databases have group levels
+    groups: Option<Groups>,
+    /// Parameters of the filters the loaded singleton kmers went through
+    filter_params: Vec<(String, String)>,
+    /// Whether only the singleton kmers of some database were loaded
+    singletons_only: bool,
+}
+
+impl<K: Kmer> Base<K> {
+    /// Load the kmers of the classes passing `keep` from a database and its
+    /// shared kmers, registering the classes in order. Without the shared
+    /// kmers, only the singleton kmers of each class are loaded. Returns all
+    /// the class names of the database.
+    fn load(
+        &mut self,
+        bincode_file: &str,
+        keep: impl Fn(&str) -> bool,
+        classes: &mut ClassNames,
+        require_shared: bool,
+    ) -> Vec<String> {
+        let singleton_kmers = load_kmer_db::<K>(bincode_file);
+        let params = singleton_kmers
+            .header
+            .params
+            .iter()
+            .cloned()
+            .collect::<HashMap<_, _>>();
+        // Shared kmers cannot tell which kmers would pass these filters
+        let filter = ["sampling", "reads", "max_occurrences"]
+            .into_iter()
+            .find(|key| params.contains_key(*key));
+        self.load_groups(&singleton_kmers, &params, bincode_file, &keep);
+        let shared_file = shared_kmers_file(bincode_file);
+        let mut kmers = singleton_kmers.kmers;
+        if require_shared {
+            if let Some(key) = filter {
+                panic!(
+                    "`{}` was built with a {} filter, so its shared kmers cannot be used, please rebuild it without",
+                    bincode_file, key
+                );
+            }
+        }
+        if require_shared || (filter.is_none() && Path::new(&shared_file).exists()) {
+            let shared_kmers = read_shared_kmers::<K>(&shared_file);
+            assert!(
+                shared_kmers.fasta_files == singleton_kmers.fasta_files,
+                "shared kmers do not match the classes of `{}`",
+                bincode_file
+            );
+            for (&kmer, &class) in shared_kmers.kmers.iter().zip(shared_kmers.classes.iter()) {
+                kmers[class as usize].push(kmer);
+            }
+        } else {
+            assert!(
+                singleton_kmers.groups.is_empty(),
+                "`{}` has group levels but no usable shared kmers, so its group kmers cannot be recomputed, please rebuild it with --keep-shared",
+                bincode_file
+            );
+            log::warn!(
+                "`{}` has no usable shared kmers, so only its singleton kmers are loaded",
+                bincode_file
+            );
+            self.singletons_only = true;
+        }
+        if self.kmers.is_empty() {
+            self.sampling = singleton_kmers.sampling;
+        }
+        assert!(
+            singleton_kmers.sampling == self.sampling,
+            "`{}` samples {}, unlike the other databases ({})",
+            bincode_file,
+            singleton_kmers.sampling,
+            self.sampling
+        );
+        let by_record = params.get("by_record").is_some_and(|x| x == "true");
+        self.by_record |= by_record;
+        for key in ["max_occurrences", "reads", "min_support", "max_support"] {
+            if let Some(value) = params.get(key) {
+                if !self.filter_params.iter().any(|(x, _)| x == key) {
+                    self.filter_params.push((key.to_string(), value.clone()));
+                }
+            }
+        }
+        let mut loaded = 0;
+        for (name, kmers) in singleton_kmers.fasta_files.iter().zip(kmers) {
+            if !keep(name) {
+                continue;
+            }
+            let class = classes.add(name);
+            if class >= self.kmers.len() {
+                self.kmers.resize_with(class + 1, Vec::new);
+            }
+            self.kmers[class].extend(kmers);
+            loaded += 1;
+        }
+        let mut fasta_files = params
+            .get("fasta_files")
+            .map(|x| x.split(',').map(String::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if !by_record {
+            fasta_files.retain(|x| keep(&prefix_until_dot(x)));
+        }
+        self.fasta_files.extend(fasta_files);
+        log::info!(
+            "Loaded {} of {} classes from `{}`",
+            loaded,
+            singleton_kmers.fasta_files.len(),
+            bincode_file
+        );
+        singleton_kmers.fasta_files
+    }
+
+    /// Add the groups of the classes passing `keep` in a database
+    fn load_groups(
+        &mut self,
+        singleton_kmers: &SingletonKmers<K>,
+        params: &HashMap<String, String>,
+        bincode_file: &str,
+        keep: impl Fn(&str) -> bool,
+    ) {
+        if singleton_kmers.groups.is_empty() {
+            return;
+        }
+        let levels = singleton_kmers
+            .groups
+            .iter()
+            .map(|level| level.name.clone())
+            .collect::<Vec<_>>();
+        let groups = self.groups.get_or_insert_with(|| Groups {
+            source: params
+                .get("groups")
                 .cloned()
-                .collect::<Vec<_>>();
+                .unwrap_or_else(|| bincode_file.to_string()),
+            levels: levels.clone(),
+            assigned: HashMap::new(),
+        });
+        assert!(
+            groups.levels == levels,
+            "`{}` has group levels {}, unlike the other databases ({})",
+            bincode_file,
+            levels.join(","),
+            groups.levels.join(",")
+        );
+        for (c, name) in singleton_kmers.fasta_files.iter().enumerate() {
+            if keep(name) {
+                let assigned = singleton_kmers
+                    .groups
+                    .iter()
+                    .map(|level| level.groups[level.members[c]].clone())
+                    .collect();
+                groups.assigned.insert(name.clone(), assigned);
+            }
+        }
+    }
+
+    /// Take the kmers of each class, sorted and deduplicated
+    fn take_class_kmers(&mut self, class_names: &[String]) -> Vec<ClassKmers<K>> {
+        std::mem::take(&mut self.kmers)
+            .into_par_iter()
+            .zip(class_names.par_iter())
+            .map(|(kmers, name)| sort_kmers(kmers, name, false))
+            .collect()
+    }
+}
+
+/// Combine the kmers of the kept classes with those read from the added FASTA
+/// files, which come last unless records add to a kept class
+fn add_base<K: Kmer>(
+    base: Vec<ClassKmers<K>>,
+    added: Vec<ClassKmers<K>>,
+    class_names: &[String],
+) -> Vec<ClassKmers<K>> {
+    let offset = class_names.len() - added.len();
+    let mut all_kmers = base;
+    all_kmers.resize_with(class_names.len(), || ClassKmers {
+        kmers: Vec::new(),
+        counts: Vec::new(),
+    });
+    for (i, added) in added.into_iter().enumerate() {
+        let class = offset + i;
+        if added.kmers.is_empty() {
+            continue;
+        }
+        let mut kmers = std::mem::take(&mut all_kmers[class].kmers);
+        kmers.extend(added.kmers);
+        all_kmers[class] = sort_kmers(kmers, &class_names[class], false);
+    }
+    all_kmers
+}
+
+/// Class names, either one per input file or assigned from FASTA record names
+struct ClassNames {
+    names: Vec<String>,
+    indices: HashMap<String, usize>,
+    /// Record name => class name
+    class_map: HashMap<String, String>,
+}
+
+impl ClassNames {
+    fn new(class_map_file: Option<&str>) -> Self {
+        let mut class_map = HashMap::new();
+        if let Some(class_map_file) = class_map_file {
+            let reader = ReaderBuilder::new()
+                .delimiter(b'\t')
+                .has_headers(false)
+                .flexible(true)
+                .from_path(class_map_file)
+                .expect("valid class map TSV");
+            for record in reader.into_records() {
+                let record = record.expect("valid record");
+                assert!(
+                    record.len() >= 2,
+                    "`{}` expects a record name and a class name on each row, got `{}`",
+                    class_map_file,
+                    record.iter().collect::<Vec<_>>().join("\t")
+                );
+                class_map.insert(record[0].to_string(), record[1].to_string());
+            }
             log::info!(
-                "{}: {} singleton kmers found",
-                fasta_file,
-                singleton_kmers_per_file.len()
+                "Loaded {} records to group into classes from `{}`",
+                class_map.len(),
+                class_map_file
+            );
+        }
+        ClassNames {
+            names: Vec::new(),
+            indices: HashMap::new(),
+            class_map,
+        }
+    }
+
+    /// Get the class index by name, adding it if new
+    fn add(&mut self, name: &str) -> usize {
+        if let Some(&i) = self.indices.get(name) {
+            return i;
+        }
+        // Kmer indices store class indices as u16
+        assert!(
+            self.names.len() < u16::MAX as usize,
+            "too many classes, at most {} are supported",
+            u16::MAX
+        );
+        self.names.push(name.to_string());
+        self.indices.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    /// Get the class index of a FASTA record
+    fn of_record(&mut self, record_name: &str) -> usize {
+        match self.class_map.get(record_name) {
+            Some(name) => {
+                let name = name.clone();
+                self.add(&name)
+            }
+            None => {
+                if !self.class_map.is_empty() {
+                    log::warn!(
+                        "`{}` is not in the class map, using its own class",
+                        record_name
+                    );
+                }
+                self.add(record_name)
+            }
+        }
+    }
+
+    #[inline]
+    fn n(&self) -> usize {
+        self.names.len()
+    }
+}
+
+/// Group of each class at each level of a class hierarchy
+struct Groups {
+    /// Groups TSV or database the hierarchy comes from
+    source: String,
+    /// Level names, from fine to coarse
+    levels: Vec<String>,
+    /// Class name => group name at each level
+    assigned: HashMap<String, Vec<String>>,
+}
+
+impl Groups {
+    /// Load the class hierarchy from a TSV with a header row of level names,
+    /// where each row has a class followed by its group at each level
+    fn load(groups_file: &str) -> Self {
+        let mut reader = ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(true)
+            .from_path(groups_file)
+            .expect("valid groups TSV");
+        let header = reader.headers().expect("valid header").clone();
+        let mut assigned = HashMap::new();
+        for record in reader.into_records() {
+            let record = record.expect("valid record");
+            let groups = record.iter().skip(1).map(String::from).collect::<Vec<_>>();
+            assert!(
+                groups.len() == header.len() - 1,
+                "`{}` expects {} levels for `{}`",
+                groups_file,
+                header.len() - 1,
+                &record[0]
             );
-            singleton_kmers_per_file
+            assigned.insert(record[0].to_string(), groups);
+        }
+        Groups {
+            source: groups_file.to_string(),
+            levels: header.iter().skip(1).map(String::from).collect(),
+            assigned,
+        }
+    }
+}
+
+/// Build the group levels of the classes from their hierarchy. Levels go from
+/// fine to coarse and must be nested. Classes not listed form their own group.
+fn group_levels<K: Kmer>(groups: Option<&Groups>, class_names: &[String]) -> Vec<GroupLevel<K>> {
+    let Some(groups) = groups else {
+        return Vec::new();
+    };
+    let mut levels = groups
+        .levels
+        .iter()
+        .map(|name| GroupLevel {
+            name: name.clone(),
+            ..Default::default()
         })
         .collect::<Vec<_>>();
-    let fasta_files = fasta_files
+    for (l, level) in levels.iter_mut().enumerate() {
+        let mut indices = HashMap::new();
+        for class_name in class_names.iter() {
+            let group = match groups.assigned.get(class_name) {
+                Some(assigned) => &assigned[l],
+                None => class_name,
+            };
+            let index = *indices.entry(group.clone()).or_insert_with(|| {
+                level.groups.push(group.clone());
+                level.groups.len() - 1
+            });
+            level.members.push(index);
+        }
+        level.kmers = vec![Vec::new(); level.groups.len()];
+    }
+    for (l, level) in levels.iter().enumerate().skip(1) {
+        let mut parents = HashMap::new();
+        for (c, &group) in level.members.iter().enumerate() {
+            let child = levels[l - 1].members[c];
+            let parent = *parents.entry(child).or_insert(group);
+            assert!(
+                parent == group,
+                "`{}`: group `{}` of level `{}` is split across level `{}`",
+                groups.source,
+                levels[l - 1].groups[child],
+                levels[l - 1].name,
+                level.name
+            );
+        }
+    }
+    let unassigned = class_names
         .iter()
-        .map(|x| prefix_until_dot(x))
+        .filter(|x| !groups.assigned.contains_key(*x))
+        .count();
+    if unassigned > 0 {
+        log::warn!("{} classes are not in `{}`", unassigned, groups.source);
+    }
+    levels
+}
+
+/// Singleton kmers of each file, collected while merging the kmer runs
+#[derive(Clone)]
+struct Singletons<K> {
+    kmers: Vec<Vec<K>>,
+    /// Kmers unique to a group of classes at each level
+    levels: Vec<GroupLevel<K>>,
+    /// Number of distinct kmers seen
+    total: usize,
+    /// Number of kmers unique to each file but discarded as repetitive
+    repeats: Vec<usize>,
+    /// Number of kmers unique to each group but discarded as repetitive, at
+    /// each level
+    group_repeats: Vec<Vec<usize>>,
+    max_occurrences: Option<u32>,
+    /// (kmer, file index) of kmers in more than one file, if kept
+    shared: Vec<(K, u16)>,
+    keep_shared: bool,
+    count_shared: bool,
+    /// Number of kmers in each pair of classes, if counted, with the distinct
+    /// kmers of each class on the diagonal. Allocated on the first kmer, so
+    /// that copies of empty singletons stay small.
+    shared_counts: Option<Vec<Vec<u64>>>,
+}
+
+impl<K: Kmer> Singletons<K> {
+    fn new(
+        n: usize,
+        levels: &[GroupLevel<K>],
+        max_occurrences: Option<u32>,
+        keep_shared: bool,
+    ) -> Self {
+        Singletons {
+            kmers: vec![Vec::new(); n],
+            levels: levels.to_vec(),
+            total: 0,
+            repeats: vec![0; n],
+            group_repeats: levels.iter().map(|x| vec![0; x.groups.len()]).collect(),
+            max_occurrences,
+            shared: Vec::new(),
+            keep_shared,
+            count_shared: false,
+            shared_counts: None,
+        }
+    }
+
+    /// Also count the kmers shared by each pair of classes
+    fn count_shared(mut self, enabled: bool) -> Self {
+        self.count_shared = enabled;
+        self
+    }
+
+    /// Record a kmer with the files it occurs in and its occurrences in each
+    fn add(&mut self, kmer: K, file_counts: &[(usize, u32)]) {
+        self.total += 1;
+        if self.count_shared {
+            let n = self.kmers.len();
+            let shared_counts = self
+                .shared_counts
+                .get_or_insert_with(|| vec![vec![0; n]; n]);
+            for &(i, _) in file_counts.iter() {
+                for &(j, _) in file_counts.iter() {
+                    shared_counts[i][j] += 1;
+                }
+            }
+        }
+        match file_counts {
+            [(file_index, count)] => {
+                if self.max_occurrences.is_some_and(|x| *count > x) {
+                    self.repeats[*file_index] += 1;
+                } else {
+                    self.kmers[*file_index].push(kmer);
+                }
+            }
+            [(first, _), rest @ ..] => {
+                if self.keep_shared {
+                    for &(file_index, _) in file_counts.iter() {
+                        let file_index =
+                            u16::try_from(file_index).expect("class index to fit in u16");
+                        self.shared.push((kmer, file_index));
+                    }
+                }
+                // Kept at the finest level where all the classes share a group,
+                // unless repetitive in any of them
+                let repetitive = self
+                    .max_occurrences
+                    .is_some_and(|x| file_counts.iter().any(|&(_, count)| count > x));
+                for (level, repeats) in self.levels.iter_mut().zip(self.group_repeats.iter_mut()) {
+                    let group = level.members[*first];
+                    if rest.iter().all(|&(x, _)| level.members[x] == group) {
+                        if repetitive {
+                            repeats[group] += 1;
+                        } else {
+                            level.kmers[group].push(kmer);
+                        }
+                        break;
+                    }
+                }
+            }
+            [] => {}
+        }
+    }
+
+    /// Take the kmers of the classes followed by those of the groups at each
+    /// level, with their names, so that they can be filtered together
+    fn take_kmers(&mut self, class_names: &[String]) -> (Vec<String>, Vec<Vec<K>>) {
+        let mut names = class_names.to_vec();
+        let mut kmers = std::mem::take(&mut self.kmers);
+        for level in self.levels.iter_mut() {
+            names.extend(level.groups.iter().map(|x| format!("{} {}", level.name, x)));
+            kmers.append(&mut level.kmers);
+        }
+        (names, kmers)
+    }
+
+    /// Put back the kmers taken by `take_kmers`
+    fn restore_kmers(&mut self, mut kmers: Vec<Vec<K>>) {
+        for level in self.levels.iter_mut().rev() {
+            level.kmers = kmers.split_off(kmers.len() - level.groups.len());
+        }
+        self.kmers = kmers;
+    }
+
+    /// Log the number of kmers found for each class and group
+    fn log(&self, class_names: &[String]) {
+        log::info!("Total unique kmers: {}", self.total);
+        log::info!(
+            "Singleton kmers: {}",
+            self.kmers.iter().map(|x| x.len()).sum::<usize>()
+        );
+        for (name, kmers) in class_names.iter().zip(self.kmers.iter()) {
+            log::info!("{}: {} singleton kmers found", name, kmers.len());
+        }
+        if let Some(max_occurrences) = self.max_occurrences {
+            for (name, repeats) in class_names.iter().zip(self.repeats.iter()) {
+                log::info!(
+                    "{}: {} repetitive kmers (> {} occurrences) discarded",
+                    name,
+                    repeats,
+                    max_occurrences
+                );
+            }
+        }
+        for (level, repeats) in self.levels.iter().zip(self.group_repeats.iter()) {
+            for (i, (name, kmers)) in level.groups.iter().zip(level.kmers.iter()).enumerate() {
+                log::info!("{} {}: {} group kmers found", level.name, name, kmers.len());
+                if let Some(max_occurrences) = self.max_occurrences {
+                    log::info!(
+                        "{} {}: {} repetitive group kmers (> {} occurrences) discarded",
+                        level.name,
+                        name,
+                        repeats[i],
+                        max_occurrences
+                    );
+                }
+            }
+        }
+    }
+
+    /// Append singletons collected from a later part of the kmer space
+    fn append(&mut self, mut other: Singletons<K>) {
+        for (kmers, other_kmers) in self.kmers.iter_mut().zip(other.kmers.iter_mut()) {
+            kmers.append(other_kmers);
+        }
+        for (level, other_level) in self.levels.iter_mut().zip(other.levels.iter_mut()) {
+            for (kmers, other_kmers) in level.kmers.iter_mut().zip(other_level.kmers.iter_mut()) {
+                kmers.append(other_kmers);
+            }
+        }
+        for (repeats, other_repeats) in self.repeats.iter_mut().zip(other.repeats.iter()) {
+            *repeats += other_repeats;
+        }
+        for (repeats, other_repeats) in self
+            .group_repeats
+            .iter_mut()
+            .zip(other.group_repeats.iter())
+        {
+            for (count, other_count) in repeats.iter_mut().zip(other_repeats.iter()) {
+                *count += other_count;
+            }
+        }
+        self.shared.append(&mut other.shared);
+        match (self.shared_counts.as_mut(), other.shared_counts) {
+            (Some(shared_counts), Some(other_shared_counts)) => {
+                for (counts, other_counts) in
+                    shared_counts.iter_mut().zip(other_shared_counts.iter())
+                {
+                    for (count, other_count) in counts.iter_mut().zip(other_counts.iter()) {
+                        *count += other_count;
+                    }
+                }
+            }
+            (None, other_shared_counts) => self.shared_counts = other_shared_counts,
+            (Some(_), None) => {}
+        }
+        self.total += other.total;
+    }
+}
+
+/// Write the class-by-class shared kmer counts as a TSV matrix
+fn write_shared_matrix(shared_matrix: &str, class_names: &[String], counts: &[Vec<u64>]) {
+    let mut writer = BufWriter::new(File::create(shared_matrix).unwrap());
+    writeln!(writer, "Class\t{}", class_names.join("\t")).unwrap();
+    for (name, row) in class_names.iter().zip(counts.iter()) {
+        let row = row.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        writeln!(writer, "{}\t{}", name, row.join("\t")).unwrap();
+    }
+    writer.flush().unwrap();
+    log::info!("Shared kmer matrix written to `{}`", shared_matrix);
+}
+
+/// Keep only the singleton kmers that are sampled from the reference. Kmers
+/// are checked for uniqueness before sampling, since a kmer sampled in one
+/// class may occur unsampled in another.
+fn filter_by_sampling<K: Kmer>(
+    kmers: Vec<Vec<K>>,
+    kmer_size: u8,
+    class_names: &[String],
+    fasta_files: &[String],
+    sampling: Sampling,
+) -> Vec<Vec<K>> {
+    let kmer_index = KmerIndex::new(kmer_size, kmers);
+    let sampled = (0..kmer_index.len())
+        .map(|_| AtomicBool::new(false))
         .collect::<Vec<_>>();
-    // Serialize the singleton kmers to a file
-    let singleton_kmers = SingletonKmers {
-        kmer_size,
-        fasta_files,
-        kmers: singletons,
+    fasta_files.par_iter().for_each(|fasta_file| {
+        log::info!("Sampling {} in `{}`", sampling, fasta_file);
+        for_each_kmer(
+            fasta_file,
+            kmer_size,
+            sampling,
+            |_| 0,
+            |_, kmer| {
+                if let Some(i) = kmer_index.position(kmer) {
+                    sampled[i].store(true, Ordering::Relaxed);
+                }
+            },
+        );
+    });
+    let n = class_names.len();
+    let before = kmer_index.counts(n);
+    let kmers = kmer_index.filter_kmer_lists(n, |i| sampled[i].load(Ordering::Relaxed));
+    for ((name, before), kmers) in class_names.iter().zip(before).zip(kmers.iter()) {
+        log::info!(
+            "{}: {} of {} singleton kmers sampled",
+            name,
+            kmers.len(),
+            before
+        );
+    }
+    kmers
+}
+
+/// Count the occurrences of the singleton kmers in the reads, and keep only the
+/// kmers with support between `min_support` and `max_support`. Kmers from assembly
+/// errors are rarely seen in the reads, while kmers from collapsed repeats are
+/// seen far more often than the coverage.
+fn filter_by_support<K: Kmer>(
+    kmers: Vec<Vec<K>>,
+    kmer_size: u8,
+    class_names: &[String],
+    reads_files: &[String],
+    min_support: u32,
+    max_support: Option<u32>,
+) -> Vec<Vec<K>> {
+    let kmer_index = KmerIndex::new(kmer_size, kmers);
+    let support = (0..kmer_index.len())
+        .map(|_| AtomicU32::new(0))
+        .collect::<Vec<_>>();
+    reads_files.par_iter().for_each(|reads_file| {
+        log::info!("Counting singleton kmers in `{}`", reads_file);
+        for_each_kmer(
+            reads_file,
+            kmer_size,
+            Sampling::All,
+            |_| 0,
+            |_, kmer| {
+                if let Some(i) = kmer_index.position(kmer) {
+                    support[i].fetch_add(1, Ordering::Relaxed);
+                }
+            },
+        );
+    });
+    let support = support
+        .into_iter()
+        .map(AtomicU32::into_inner)
+        .collect::<Vec<_>>();
+    let n = class_names.len();
+    let before = kmer_index.counts(n);
+    let window = min_support..=max_support.unwrap_or(u32::MAX);
+    let kmers = kmer_index.filter_kmer_lists(n, |i| window.contains(&support[i]));
+    let window = match max_support {
+        Some(max_support) => format!("[{}, {}]", min_support, max_support),
+        None => format!(">= {}", min_support),
     };
-    let writer = BufWriter::new(File::create(output_file).unwrap());
-    serialize_into(writer, &singleton_kmers).expect("serialization to succeed");
-    log::info!("Singleton kmers written to `{}`", output_file);
+    for ((name, before), kmers) in class_names.iter().zip(before).zip(kmers.iter()) {
+        log::info!(
+            "{}: {} of {} singleton kmers with read support {}",
+            name,
+            kmers.len(),
+            before,
+            window
+        );
+    }
+    kmers
+}
+
+/// Merge sorted runs of (kmer, occurrences), calling `f` with each kmer and
+/// the files it occurs in along with its total occurrences in each. Each run is
+/// tagged with its file index; a file may have several runs.
+fn merge_runs<K: Kmer, I: Iterator<Item = (K, u32)>>(
+    mut runs: Vec<(usize, I)>,
+    mut f: impl FnMut(K, &[(usize, u32)]),
+) {
+    let mut heap = BinaryHeap::new();
+    for (i, (_, run)) in runs.iter_mut().enumerate() {
+        if let Some((kmer, count)) = run.next() {
+            heap.push(Reverse((kmer, i, count)));
+        }
+    }
+    let mut current = None;
+    let mut file_counts: Vec<(usize, u32)> = Vec::new();
+    while let Some(Reverse((kmer, i, count))) = heap.pop() {
+        if current != Some(kmer) {
+            if let Some(current) = current {
+                f(current, &file_counts);
+            }
+            current = Some(kmer);
+            file_counts.clear();
+        }
+        let file_index = runs[i].0;
+        match file_counts.iter_mut().find(|(x, _)| *x == file_index) {
+            Some((_, total)) => *total = total.saturating_add(count),
+            None => file_counts.push((file_index, count)),
+        }
+        if let Some((next, count)) = runs[i].1.next() {
+            heap.push(Reverse((next, i, count)));
+        }
+    }
+    if let Some(current) = current {
+        f(current, &file_counts);
+    }
+}
+
+/// Sorted, distinct kmers of one class, with their occurrences if counted
+struct ClassKmers<K> {
+    kmers: Vec<K>,
+    /// Occurrences of each kmer, empty if not counted
+    counts: Vec<u32>,
 }
 
-/// Get kmers from a FASTA file
-fn get_kmers(fasta_file: &str, kmer_size: u8) -> HashSet<u64> {
-    let mut reader = parse_fastx_file(fasta_file).expect("valid FASTA file");
-    let mut kmer_set = HashSet::new();
+impl<K: Kmer> ClassKmers<K> {
+    /// Iterate over (kmer, occurrences) in a range of the kmers
+    fn run(&self, start: usize, end: usize) -> impl Iterator<Item = (K, u32)> + '_ {
+        (start..end).map(|i| (self.kmers[i], self.counts.get(i).copied().unwrap_or(1)))
+    }
+}
+
+/// Collect the sorted, distinct kmers of each class in memory
+fn collect_in_memory<K: Kmer>(
+    fasta_files: &[String],
+    kmer_size: u8,
+    classes: &mut ClassNames,
+    by_record: bool,
+    count: bool,
+) -> Vec<ClassKmers<K>> {
+    if by_record {
+        let mut all_kmers: Vec<Vec<K>> = Vec::new();
+        for fasta_file in fasta_files.iter() {
+            for_each_kmer(
+                fasta_file,
+                kmer_size,
+                Sampling::All,
+                |record_name| classes.of_record(record_name),
+                |class, kmer| {
+                    if class >= all_kmers.len() {
+                        all_kmers.resize_with(class + 1, Vec::new);
+                    }
+                    all_kmers[class].push(kmer);
+                },
+            );
+        }
+        all_kmers.resize_with(classes.n(), Vec::new);
+        all_kmers
+            .into_par_iter()
+            .zip(classes.names.par_iter())
+            .map(|(kmers, name)| sort_kmers(kmers, name, count))
+            .collect::<Vec<_>>()
+    } else {
+        let file_kmers = fasta_files
+            .par_iter()
+            .map(|fasta_file| {
+                let mut kmers = Vec::new();
+                for_each_kmer(
+                    fasta_file,
+                    kmer_size,
+                    Sampling::All,
+                    |_| 0,
+                    |_, kmer| kmers.push(kmer),
+                );
+                kmers
+            })
+            .collect::<Vec<_>>();
+        // Classes loaded from a database have no files
+        let mut all_kmers: Vec<Option<Vec<K>>> = vec![None; classes.n()];
+        for (fasta_file, mut kmers) in fasta_files.iter().zip(file_kmers) {
+            let class = classes.add(&prefix_until_dot(fasta_file));
+            all_kmers[class]
+                .get_or_insert_with(Vec::new)
+                .append(&mut kmers);
+        }
+        all_kmers
+            .into_par_iter()
+            .zip(classes.names.par_iter())
+            .map(|(kmers, name)| match kmers {
+                Some(kmers) => sort_kmers(kmers, name, count),
+                None => ClassKmers {
+                    kmers: Vec::new(),
+                    counts: Vec::new(),
+                },
+            })
+            .collect::<Vec<_>>()
+    }
+}
+
+/// Sort and deduplicate the kmers of one class, optionally counting them
+fn sort_kmers<K: Kmer>(mut kmers: Vec<K>, name: &str, count: bool) -> ClassKmers<K> {
+    kmers.sort_unstable();
+    let mut counts = Vec::new();
+    if count {
+        counts = count_sorted(&mut kmers);
+        counts.shrink_to_fit();
+    } else {
+        kmers.dedup();
+    }
+    kmers.shrink_to_fit();
+    log::info!("{}: {} kmers found", name, kmers.len());
+    ClassKmers { kmers, counts }
+}
+
+/// Deduplicate sorted kmers in place, returning the occurrences of each
+fn count_sorted<K: Kmer>(kmers: &mut Vec<K>) -> Vec<u32> {
+    let mut counts: Vec<u32> = Vec::new();
+    let mut n = 0;
+    for i in 0..kmers.len() {
+        if n > 0 && kmers[n - 1] == kmers[i] {
+            counts[n - 1] = counts[n - 1].saturating_add(1);
+        } else {
+            kmers[n] = kmers[i];
+            counts.push(1);
+            n += 1;
+        }
+    }
+    kmers.truncate(n);
+    counts
+}
+
+/// Merge the sorted kmers of all classes held in memory. The kmer space is
+/// split into ranges by leading bits so that the ranges are merged in parallel.
+fn merge_in_memory<K: Kmer>(
+    all_kmers: Vec<ClassKmers<K>>,
+    kmer_size: u8,
+    init: &Singletons<K>,
+) -> Singletons<K> {
+    let kmer_bits = 2 * kmer_size as u32;
+    let range_bits = (rayon::current_num_threads() * 4)
+        .next_power_of_two()
+        .ilog2()
+        .min(kmer_bits);
+    let shift = kmer_bits - range_bits;
+    (0..1u64 << range_bits)
+        .into_par_iter()
+        .map(|range| {
+            let runs = all_kmers
+                .iter()
+                .enumerate()
+                .map(|(i, class_kmers)| {
+                    let kmers = &class_kmers.kmers;
+                    let start = kmers.partition_point(|&x| x.high_bits(shift) < range);
+                    let end = kmers.partition_point(|&x| x.high_bits(shift) <= range);
+                    (i, class_kmers.run(start, end))
+                })
+                .collect::<Vec<_>>();
+            let mut singletons = init.clone();
+            merge_runs(runs, |kmer, file_counts| singletons.add(kmer, file_counts));
+            singletons
+        })
+        // Ranges are appended in order as they finish
+        .reduce(
+            || init.clone(),
+            |mut acc, x| {
+                acc.append(x);
+                acc
+            },
+        )
+}
+
+/// Sort kmers within a memory budget (in GB). Kmers of each class are sorted in
+/// chunks that fit in the budget and written to temporary runs, which are then
+/// compacted into one sorted run of distinct kmers (with their occurrences) per
+/// class.
+fn sort_on_disk<K: Kmer>(
+    fasta_files: &[String],
+    kmer_size: u8,
+    classes: &mut ClassNames,
+    by_record: bool,
+    max_memory: f64,
+    tmp_dir: &str,
+) -> Vec<String> {
+    let run_files = if by_record {
+        // Records are read one at a time, so one buffer takes the whole budget.
+        // Each buffered kmer may get a u32 count when its run is written.
+        let buffer_len = ((max_memory * 1e9 / (K::BYTES + 4) as f64) as usize).max(1 << 16);
+        log::info!(
+            "Sorting kmers on disk in `{}` ({} kmers per run)",
+            tmp_dir,
+            buffer_len
+        );
+        let mut writer = RunWriter::<K>::new(tmp_dir, buffer_len);
+        for fasta_file in fasta_files.iter() {
+            for_each_kmer(
+                fasta_file,
+                kmer_size,
+                Sampling::All,
+                |record_name| classes.of_record(record_name),
+                |class, kmer| writer.push(class, kmer),
+            );
+        }
+        let mut run_files = vec![Vec::new(); classes.n()];
+        for (class, run_file) in writer.finish() {
+            run_files[class].push(run_file);
+        }
+        run_files
+    } else {
+        // Each thread fills its own buffer of kmers, counted as above
+        let buffer_len = (max_memory * 1e9
+            / (K::BYTES + 4) as f64
+            / rayon::current_num_threads() as f64) as usize;
+        let buffer_len = buffer_len.max(1 << 16);
+        log::info!(
+            "Sorting kmers on disk in `{}` ({} kmers per run)",
+            tmp_dir,
+            buffer_len
+        );
+        // Runs are named by file, as files may share a class
+        let file_runs = fasta_files
+            .par_iter()
+            .enumerate()
+            .map(|(i, fasta_file)| {
+                let mut writer = RunWriter::<K>::new(tmp_dir, buffer_len);
+                for_each_kmer(
+                    fasta_file,
+                    kmer_size,
+                    Sampling::All,
+                    |_| i,
+                    |file_index, kmer| writer.push(file_index, kmer),
+                );
+                writer.finish()
+            })
+            .collect::<Vec<_>>();
+        let mut run_files = vec![Vec::new(); classes.n()];
+        for (fasta_file, runs) in fasta_files.iter().zip(file_runs) {
+            let class = classes.add(&prefix_until_dot(fasta_file));
+            run_files[class].extend(runs.into_iter().map(|(_, run_file)| run_file));
+        }
+        run_files
+    };
+    run_files
+        .into_par_iter()
+        .zip(classes.names.par_iter())
+        .enumerate()
+        .map(|(class, (run_files, name))| compact_runs::<K>(tmp_dir, class, run_files, name))
+        .collect::<Vec<_>>()
+}
+
+/// Merge the sorted runs of all classes from disk. Only the singleton kmers
+/// are kept in memory.
+fn merge_on_disk<K: Kmer>(run_files: &[String], init: &Singletons<K>) -> Singletons<K> {
+    let runs = run_files
+        .iter()
+        .enumerate()
+        .map(|(i, run_file)| (i, RunReader::<K>::new(run_file)))
+        .collect::<Vec<_>>();
+    let mut singletons = init.clone();
+    merge_runs(runs, |kmer, file_counts| singletons.add(kmer, file_counts));
+    singletons
+}
+
+/// Merge the runs of one class into a single sorted run of distinct kmers
+fn compact_runs<K: Kmer>(
+    tmp_dir: &str,
+    class: usize,
+    run_files: Vec<String>,
+    name: &str,
+) -> String {
+    let merged_file = format!("{}/{}.run", tmp_dir, class);
+    let runs = run_files
+        .iter()
+        .map(|run_file| (class, RunReader::<K>::new(run_file)))
+        .collect::<Vec<_>>();
+    let mut writer = BufWriter::new(File::create(&merged_file).unwrap());
+    let mut total = 0;
+    merge_runs(runs, |kmer, file_counts| {
+        write_run_entry(&mut writer, kmer, file_counts[0].1);
+        total += 1;
+    });
+    writer.flush().unwrap();
+    for run_file in run_files {
+        std::fs::remove_file(run_file).expect("valid remove");
+    }
+    log::info!("{}: {} kmers found", name, total);
+    merged_file
+}
+
+/// Buffers kmers by class and writes them out as sorted runs, one per class,
+/// once the buffers hold `buffer_len` kmers. Switching class does not flush,
+/// so interleaved records of many classes do not fragment the runs.
+struct RunWriter<'a, K> {
+    tmp_dir: &'a str,
+    buffers: Vec<Vec<K>>,
+    len: usize,
+    buffer_len: usize,
+    run_files: Vec<(usize, String)>,
+}
+
+impl<'a, K: Kmer> RunWriter<'a, K> {
+    fn new(tmp_dir: &'a str, buffer_len: usize) -> Self {
+        RunWriter {
+            tmp_dir,
+            buffers: Vec::new(),
+            len: 0,
+            buffer_len,
+            run_files: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, class: usize, kmer: K) {
+        if self.len == self.buffer_len {
+            self.flush();
+        }
+        if class >= self.buffers.len() {
+            self.buffers.resize_with(class + 1, Vec::new);
+        }
+        self.buffers[class].push(kmer);
+        self.len += 1;
+    }
+
+    fn flush(&mut self) {
+        for class in 0..self.buffers.len() {
+            // Release the memory, as the next chunk may hold other classes
+            let mut buffer = std::mem::take(&mut self.buffers[class]);
+            if buffer.is_empty() {
+                continue;
+            }
+            buffer.sort_unstable();
+            let counts = count_sorted(&mut buffer);
+            let run_file = format!("{}/{}.{}.run", self.tmp_dir, class, self.run_files.len());
+            let mut writer = BufWriter::new(File::create(&run_file).unwrap());
+            for (&kmer, &count) in buffer.iter().zip(counts.iter()) {
+                write_run_entry(&mut writer, kmer, count);
+            }
+            writer.flush().unwrap();
+            self.run_files.push((class, run_file));
+        }
+        self.len = 0;
+    }
+
+    /// Write out the remaining kmers, returning the runs with their classes
+    fn finish(mut self) -> Vec<(usize, String)> {
+        self.flush();
+        self.run_files
+    }
+}
+
+/// Write a kmer and its occurrences to a run
+#[inline]
+fn write_run_entry<K: Kmer>(writer: &mut impl Write, kmer: K, count: u32) {
+    kmer.write_le(writer).unwrap();
+    writer.write_all(&count.to_le_bytes()).unwrap();
+}
+
+/// Read back a sorted run of (kmer, occurrences) written by `RunWriter`
+struct RunReader<K> {
+    reader: BufReader<File>,
+    kmer: PhantomData<K>,
+}
+
+impl<K> RunReader<K> {
+    fn new(run_file: &str) -> Self {
+        RunReader {
+            reader: BufReader::new(File::open(run_file).unwrap()),
+            kmer: PhantomData,
+        }
+    }
+}
+
+impl<K: Kmer> Iterator for RunReader<K> {
+    type Item = (K, u32);
+
+    fn next(&mut self) -> Option<(K, u32)> {
+        let mut buf = [0u8; 20];
+        let buf = &mut buf[..K::BYTES + 4];
+        self.reader.read_exact(buf).ok().map(|_| {
+            let (kmer, count) = buf.split_at(K::BYTES);
+            (
+                K::from_le_slice(kmer),
+                u32::from_le_bytes(count.try_into().unwrap()),
+            )
+        })
+    }
+}
+
+/// Call `f` on every sampled canonical kmer in a FASTA file, along with the
+/// class of its record as given by `class_of`
+fn for_each_kmer<K: Kmer>(
+    fasta_file: &str,
+    kmer_size: u8,
+    sampling: Sampling,
+    mut class_of: impl FnMut(&str) -> usize,
+    mut f: impl FnMut(usize, K),
+) {
+    let mut reader = open_fastx(fasta_file).expect("valid FASTA file");
     while let Some(record) = reader.next() {
         let record = record.expect("valid record");
+        // Get the first part of the ID
+        let id = String::from_utf8_lossy(record.id());
+        let class = class_of(id.split_whitespace().next().unwrap_or_default());
         let seq = record.normalize(false);
-        for (_, kmer, _) in seq.bit_kmers(kmer_size, true) {
-            kmer_set.insert(kmer.0);
+        for_each_sampled_kmer(&seq, kmer_size, sampling, |_, kmer| f(class, kmer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::random_seq;
+    use tempfile::TempDir;
+
+    /// Write records of (id, sequence) to `file` in `dir`, returning its path
+    fn write_fasta(dir: &Path, file: &str, records: &[(&str, &str)]) -> String {
+        let path = dir.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut writer = File::create(&path).unwrap();
+        for (id, seq) in records.iter() {
+            writeln!(writer, ">{}\n{}", id, seq).unwrap();
+        }
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Classes A to D as one file each and as records of `all.fa`, with kmers
+    /// shared within and across the groups of `groups.tsv`. Returns the paths
+    /// of the class files, `all.fa` and `groups.tsv`.
+    fn write_classes(dir: &Path) -> (Vec<String>, String, String) {
+        // Shared segments: A and B, C and D, A and C, and all of them
+        let shared = [
+            ("A", vec![10, 12, 13]),
+            ("B", vec![10, 13]),
+            ("C", vec![11, 12, 13]),
+            ("D", vec![11, 13]),
+        ];
+        let seqs = shared
+            .iter()
+            .enumerate()
+            .map(|(i, (name, seeds))| {
+                let mut seq = random_seq(i as u64, 400);
+                for &seed in seeds.iter() {
+                    seq += &random_seq(seed, 200);
+                }
+                (*name, seq)
+            })
+            .collect::<Vec<_>>();
+        let files = seqs
+            .iter()
+            .map(|(name, seq)| write_fasta(dir, &format!("{}.fa", name), &[(name, seq)]))
+            .collect();
+        let records = seqs
+            .iter()
+            .map(|(name, seq)| (*name, seq.as_str()))
+            .collect::<Vec<_>>();
+        let all = write_fasta(dir, "all.fa", &records);
+        let groups = dir.join("groups.tsv");
+        std::fs::write(
+            &groups,
+            "class\thom\tsub\nA\tH1\tS1\nB\tH1\tS1\nC\tH2\tS1\nD\tH2\tS1\n",
+        )
+        .unwrap();
+        (files, all, groups.to_str().unwrap().to_string())
+    }
+
+    /// Check two databases hold the same classes, groups and kmers
+    fn assert_same_db(db: &SingletonKmers, expected: &SingletonKmers) {
+        let sorted = |kmers: &[Vec<u64>]| {
+            let mut kmers = kmers.to_vec();
+            kmers.iter_mut().for_each(|x| x.sort_unstable());
+            kmers
+        };
+        assert_eq!(db.fasta_files, expected.fasta_files);
+        assert_eq!(sorted(&db.kmers), sorted(&expected.kmers));
+        assert_eq!(db.groups.len(), expected.groups.len());
+        for (level, expected_level) in db.groups.iter().zip(expected.groups.iter()) {
+            assert_eq!(level.name, expected_level.name);
+            assert_eq!(level.groups, expected_level.groups);
+            assert_eq!(level.members, expected_level.members);
+            assert_eq!(sorted(&level.kmers), sorted(&expected_level.kmers));
         }
     }
-    log::info!("{}: {} kmers found", fasta_file, kmer_set.len());
-    kmer_set
+
+    /// Run `klassify build` with `args` and load the database it writes
+    fn build_db(args: &[&str]) -> SingletonKmers {
+        let args = BuildArgs::parse_from(["build"].iter().chain(args));
+        build(&args);
+        load_kmer_db(&args.output_file)
+    }
+
+    #[test]
+    fn test_build_shared_prefix() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path();
+        let (a, b, c) = (random_seq(1, 500), random_seq(2, 500), random_seq(3, 500));
+        let x = write_fasta(path, "X.fa", &[("a", &a)]);
+        let x_hap2 = write_fasta(path, "X.hap2.fa", &[("b", &b)]);
+        let y = write_fasta(path, "Y.fa", &[("c", &c)]);
+        let x_both = write_fasta(path, "both/X.fa", &[("a", &a), ("b", &b)]);
+        let expected = build_db(&[
+            &x_both,
+            &y,
+            "-k",
+            "15",
+            "-o",
+            &format!("{}/both.bc", path.display()),
+        ]);
+        assert_eq!(expected.fasta_files, ["X", "Y"]);
+        assert!(expected.kmers.iter().all(|kmers| !kmers.is_empty()));
+        for max_memory in [None, Some("0.001")] {
+            let output = format!("{}/split.bc", path.display());
+            let mut args = vec![x.as_str(), &x_hap2, &y, "-k", "15", "-o", &output];
+            if let Some(max_memory) = max_memory {
+                args.extend(["--max-memory", max_memory]);
+            }
+            let db = build_db(&args);
+            assert_eq!(db.fasta_files, expected.fasta_files);
+            assert_eq!(db.kmers, expected.kmers);
+        }
+    }
+    #[test]
+    #[should_panic(expected = "too many classes")]
+    fn test_too_many_classes() {
+        let mut classes = ClassNames::new(None);
+        for i in 0..u16::MAX as usize {
+            assert_eq!(classes.add(&i.to_string()), i);
+        }
+        classes.add("one more");
+    }
+    #[test]
+    fn test_build_max_memory() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path();
+        let (files, all, groups) = write_classes(path);
+        let output = format!("{}/kmers.bc", path.display());
+        for by_record in [false, true] {
+            let mut args = vec!["-k", "15", "-o", &output, "--groups", &groups];
+            if by_record {
+                args.extend(["--by-record", &all]);
+            } else {
+                args.extend(files.iter().map(|x| x.as_str()));
+            }
+            let expected = build_db(&args);
+            assert_eq!(expected.fasta_files, ["A", "B", "C", "D"]);
+            assert!(expected
+                .groups
+                .iter()
+                .all(|level| level.kmers.iter().all(|x| !x.is_empty())));
+            args.extend(["--max-memory", "0.001"]);
+            assert_same_db(&build_db(&args), &expected);
+        }
+    }
+    #[test]
+    fn test_build_update() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path();
+        let (files, _, groups) = write_classes(path);
+        let db_file = |name: &str| format!("{}/{}.bc", path.display(), name);
+        let common = ["-k", "15", "--keep-shared", "--groups", &groups];
+        let full = db_file("full");
+        let mut args = files.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        args.extend(common.iter().chain(&["-o", &full]));
+        let expected = build_db(&args);
+
+        // Adding C and D to A and B, assigning them to their groups
+        let updated = db_file("updated");
+        let mut args = vec![files[0].as_str(), &files[1]];
+        args.extend(common.iter().chain(&["-o", &updated]));
+        build_db(&args);
+        let db = build_db(&[
+            &files[2], &files[3], "--add", "--groups", &groups, "-o", &updated,
+        ]);
+        assert_same_db(&db, &expected);
+
+        // Removing B from all of them
+        let removed = db_file("removed");
+        std::fs::copy(&full, &removed).unwrap();
+        std::fs::copy(shared_kmers_file(&full), shared_kmers_file(&removed)).unwrap();
+        let db = build_db(&["--remove", "B", "-o", &removed]);
+        let direct = db_file("direct");
+        let mut args = vec![files[0].as_str(), &files[2], &files[3]];
+        args.extend(common.iter().chain(&["-o", &direct]));
+        assert_same_db(&db, &build_db(&args));
+    }
+    #[test]
+    fn test_merge_subset() {
+        use crate::merge::{merge, subset, MergeArgs, SubsetArgs};
+        let dir = TempDir::new().unwrap();
+        let path = dir.path();
+        let (files, _, groups) = write_classes(path);
+        let db_file = |name: &str| format!("{}/{}.bc", path.display(), name);
+        let build_classes = |classes: &[usize], output: &str| {
+            let mut args = classes
+                .iter()
+                .map(|&i| files[i].as_str())
+                .collect::<Vec<_>>();
+            args.extend([
+                "-k",
+                "15",
+                "--keep-shared",
+                "--groups",
+                &groups,
+                "-o",
+                output,
+            ]);
+            build_db(&args)
+        };
+        let full = db_file("full");
+        let expected = build_classes(&[0, 1, 2, 3], &full);
+
+        // Merging A and B with C and D
+        let (ab, cd, merged) = (db_file("ab"), db_file("cd"), db_file("merged"));
+        build_classes(&[0, 1], &ab);
+        build_classes(&[2, 3], &cd);
+        merge(&MergeArgs::parse_from(["merge", &ab, &cd, "-o", &merged]));
+        assert_same_db(&load_kmer_db(&merged), &expected);
+
+        // Keeping all but B
+        let kept = db_file("kept");
+        subset(&SubsetArgs::parse_from([
+            "subset", &full, "A", "C|D", "-o", &kept,
+        ]));
+        let direct = build_classes(&[0, 2, 3], &db_file("direct"));
+        assert_same_db(&load_kmer_db(&kept), &direct);
+    }
 }
@@ -1,55 +1,188 @@
-use crate::info::{load_kmer_db, map_kmer_to_file};
-use crate::models::{prefix, ClassifyResults, SingletonKmers};
+use crate::db::{kmer_size_of, load_kmer_index};
+use crate::hmm::{format_breakpoints, Hmm, ERROR_PROB, SWITCH_PROB};
+use crate::index::{GroupIndex, KmerIndex};
+use crate::kmer::{is_wide, Kmer};
+use crate::models::{
+    assert_stdin_once, format_segments, prefix, ClassifyResults, Segment, SingletonKmers,
+};
+use crate::reads::for_each_read;
+use crate::sampling::for_each_sampled_kmer;
 
-use clap::Parser;
+use clap::{Args, Parser};
 use log;
-use needletail::{parse_fastx_file, Sequence};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::Path;
+use std::sync::mpsc::sync_channel;
 
-/// Thresholds for filtering reads
+/// Reads and bases per batch of reads classified in parallel
+const BATCH_READS: usize = 10_000;
+const BATCH_BASES: usize = 1 << 26;
+
+/// Default thresholds for filtering reads
 const KMER_THRESHOLD: i32 = 300;
 const SCORE_THRESHOLD: i32 = 50;
 const MINOR_SCORE_THRESHOLD: i32 = 10;
 
+/// Rules for keeping a read in the filtered read classifications
+#[derive(Args, Debug, Clone, Copy)]
+pub struct FilterRules {
+    /// Prefix length that the names of both classes must share
+    #[clap(short, long, default_value_t = 7)]
+    pub prefix_length: usize,
+    /// Minimum number of unique kmers in a read
+    #[clap(long, default_value_t = KMER_THRESHOLD)]
+    pub min_kmers: i32,
+    /// Minimum percentage of unique kmers from both classes
+    #[clap(long, default_value_t = SCORE_THRESHOLD)]
+    pub min_score: i32,
+    /// Minimum percentage of unique kmers from the minor class
+    #[clap(long, default_value_t = MINOR_SCORE_THRESHOLD)]
+    pub min_minor_score: i32,
+    /// Minimum log10 likelihood ratio of the read being chimeric (LOD column)
+    #[clap(long, default_value_t = 0.0)]
+    pub min_lod: f64,
+}
+
+impl fmt::Display for FilterRules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unique kmer ≧ {}, A unique + B unique ≧ {}%, B unique ≧ {}%, LOD ≧ {}, prefix length {}",
+            self.min_kmers, self.min_score, self.min_minor_score, self.min_lod, self.prefix_length
+        )
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct FilterArgs {
+    /// Read classification files written by `classify`, or its output directory
+    #[clap(required = true)]
+    pub read_classifications: Vec<String>,
+    /// Output file, `<dir>.filtered.tsv` by default for one output directory
+    #[clap(short, long)]
+    pub output_file: Option<String>,
+    #[clap(flatten)]
+    pub rules: FilterRules,
+}
+
 #[derive(Parser, Debug)]
 pub struct ClassifyArgs {
     /// Bincode file
     pub bincode_file: String,
-    /// Read file to classify
+    /// Read files to classify, in FASTA/FASTQ or unaligned BAM/CRAM format
     pub reads_file: Vec<String>,
     /// Output directory
     #[clap(short, long)]
     pub output_dir: String,
-    /// Prefix length
-    #[clap(short, long, default_value_t = 7)]
-    pub prefix_length: usize,
+    #[clap(flatten)]
+    pub rules: FilterRules,
+    /// Minimum percentage of unique kmers for classes beyond the best two to
+    /// be reported, e.g. `A,B,C:60,25,12` for reads spanning three classes;
+    /// only the best two are reported by default
+    #[clap(long)]
+    pub min_class_score: Option<i32>,
+    /// Skip the checksum of a memory-mapped database, which reads the whole file
+    #[clap(long, default_value_t = false)]
+    pub no_verify: bool,
+    /// Skip BAM/CRAM reads with a read quality (rq tag) below this, e.g. 0.99
+    #[clap(long)]
+    pub min_read_quality: Option<f32>,
+    /// Add a column with the blocks of each class along the read
+    #[clap(long, default_value_t = false)]
+    pub segments: bool,
+    /// Minimum number of consecutive, non-overlapping unique kmers of a class
+    /// to form a block
+    #[clap(long, default_value_t = 10)]
+    pub min_segment_kmers: usize,
+    /// Segment reads with a hidden Markov model instead, adding the breakpoints
+    /// with their 95% posterior confidence intervals
+    #[clap(long, default_value_t = false)]
+    pub hmm: bool,
+    /// Probability of switching class between consecutive unique kmers
+    #[clap(long, default_value_t = SWITCH_PROB)]
+    pub switch_prob: f64,
+    /// Probability that a unique kmer is from another class than its segment,
+    /// also the false hit rate of the LOD score relative to the database density
+    #[clap(long, default_value_t = ERROR_PROB)]
+    pub error_prob: f64,
 }
 
 type ReadClassification = Vec<String>;
 
+/// Models scoring the unique kmers of each read, shared by all reads
+struct Scoring {
+    /// Fraction of the reference kmers that are unique, if known
+    density: Option<f64>,
+    hmm: Option<Hmm>,
+}
+
+/// Fraction of the distinct kmers of the references that are unique to a
+/// class, from the total recorded at build time. Only sampled kmers are kept,
+/// so the total is scaled by the expected sampling rate.
+fn kmer_density<K>(singleton_kmers: &SingletonKmers<K>, n_unique: usize) -> Option<f64> {
+    let total = singleton_kmers
+        .header
+        .params
+        .iter()
+        .find(|(key, _)| key == "total_kmers")
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .filter(|&total| total > 0);
+    if total.is_none() {
+        log::warn!("No total kmer count in the database, so LOD scores ignore its density");
+    }
+    let sampled = singleton_kmers.sampling.fraction(singleton_kmers.kmer_size);
+    total.map(|total| (n_unique as f64 / (total as f64 * sampled)).min(1.0))
+}
+
 /// Classify reads based on unique (singleton) kmers.
-pub fn classify(
-    bincode_file: &str,
-    reads_files: &Vec<String>,
-    output_dir: &str,
-    prefix_length: usize,
-) {
-    let output_dir = output_dir.trim_end_matches('/');
-    let singleton_kmers = load_kmer_db(bincode_file);
-    let kmer_to_file = map_kmer_to_file(&singleton_kmers);
+pub fn classify(args: &ClassifyArgs) {
+    if is_wide(kmer_size_of(&args.bincode_file)) {
+        classify_with::<u128>(args);
+    } else {
+        classify_with::<u64>(args);
+    }
+}
+
+fn classify_with<K: Kmer>(args: &ClassifyArgs) {
+    let bincode_file = &args.bincode_file;
+    let reads_files = &args.reads_file;
+    assert_stdin_once(reads_files);
+    let output_dir = args.output_dir.trim_end_matches('/');
+    let (singleton_kmers, kmer_to_file, kmer_to_group) =
+        load_kmer_index::<K>(bincode_file, !args.no_verify);
+    assert!(
+        args.error_prob > 0.0 && args.error_prob < 0.5,
+        "error probability must be between 0 and 0.5"
+    );
+    let scoring = Scoring {
+        density: kmer_density(&singleton_kmers, kmer_to_file.len()),
+        hmm: args
+            .hmm
+            .then(|| Hmm::new(singleton_kmers.n(), args.switch_prob, args.error_prob)),
+    };
     let output_files = reads_files
         .par_iter()
-        .map(|reads_file| classify_one(&singleton_kmers, &kmer_to_file, reads_file))
+        .map(|reads_file| {
+            classify_one(
+                &singleton_kmers,
+                &kmer_to_file,
+                &kmer_to_group,
+                &scoring,
+                reads_file,
+                args,
+            )
+        })
         .collect::<Vec<_>>();
     // Move output files to the output directory
     std::fs::create_dir_all(output_dir).expect("valid output directory");
     let new_output_files = output_files
         .iter()
-        .map(|output_file| output_dir.to_string() + "/" + &output_file.split('/').last().unwrap())
+        .map(|output_file| {
+            output_dir.to_string() + "/" + output_file.split('/').next_back().unwrap()
+        })
         .collect::<Vec<_>>();
     for (output_file, new_output_file) in output_files.iter().zip(new_output_files.iter()) {
         std::fs::rename(output_file, new_output_file).expect("valid rename");
@@ -60,10 +193,76 @@ pub fn classify(
         output_dir
     );
 
+    let output_path = format!("{}.filtered.tsv", output_dir);
+    write_filtered(
+        &new_output_files,
+        &header(&singleton_kmers, args),
+        &output_path,
+        &args.rules,
+    );
+}
+
+/// Filter read classifications again with new rules, without reclassifying
+pub fn filter(args: &FilterArgs) {
+    let output_file = match (&args.output_file, args.read_classifications.as_slice()) {
+        (Some(output_file), _) => output_file.clone(),
+        (None, [dir]) if Path::new(dir).is_dir() => {
+            format!("{}.filtered.tsv", dir.trim_end_matches('/'))
+        }
+        _ => panic!("--output-file is required unless one directory is given"),
+    };
+    // Expand directories to the read classifications in them
+    let mut rcs = Vec::new();
+    for path in args.read_classifications.iter() {
+        if !Path::new(path).is_dir() {
+            rcs.push(path.clone());
+            continue;
+        }
+        let mut dir_rcs = std::fs::read_dir(path)
+            .expect("valid directory")
+            .map(|entry| entry.unwrap().path().to_string_lossy().to_string())
+            .filter(|x| x.ends_with(".read_classifications.tsv"))
+            .collect::<Vec<_>>();
+        assert!(
+            !dir_rcs.is_empty(),
+            "no .read_classifications.tsv files in `{}`",
+            path
+        );
+        dir_rcs.sort();
+        rcs.extend(dir_rcs);
+    }
+    let mut reader = BufReader::new(File::open(&rcs[0]).expect("valid read classifications"));
+    let mut header = String::new();
+    reader.read_line(&mut header).unwrap();
+    let header = header.trim_end().to_string();
+    for rc in rcs.iter().skip(1) {
+        let mut other_header = String::new();
+        BufReader::new(File::open(rc).expect("valid read classifications"))
+            .read_line(&mut other_header)
+            .unwrap();
+        assert!(
+            other_header.trim_end() == header,
+            "`{}` has different classes from `{}`",
+            rc,
+            rcs[0]
+        );
+    }
+    write_filtered(&rcs, &header, &output_file, &args.rules);
+}
+
+/// Write the reads passing the filter rules in all read classifications, with
+/// the rules in a comment line ahead of the header
+fn write_filtered(rcs: &[String], header: &str, output_path: &str, rules: &FilterRules) {
+    let lod_column = header.split('\t').position(|x| x == "LOD");
+    assert!(
+        lod_column.is_some() || rules.min_lod <= 0.0,
+        "no LOD column in `{}`, classify again to filter by --min-lod",
+        rcs[0]
+    );
     // Collect the read classifications
-    let dfs: Vec<Vec<ReadClassification>> = new_output_files
+    let dfs: Vec<Vec<ReadClassification>> = rcs
         .par_iter()
-        .map(|rc| filter_reads(rc, prefix_length))
+        .map(|rc| filter_reads(rc, rules, lod_column))
         .collect();
     let mut all_reads = Vec::new();
     for df in dfs {
@@ -72,24 +271,19 @@ pub fn classify(
 
     if all_reads.is_empty() {
         log::error!("No reads passed the filter");
-        return;
     }
-    let output_path = format!("{}.filtered.tsv", output_dir);
+    // Written even without rows, so that no stale table is left behind
     let mut writer =
-        BufWriter::new(File::create(&Path::new(&output_path)).expect("Unable to create file"));
+        BufWriter::new(File::create(Path::new(output_path)).expect("Unable to create file"));
 
-    // Write the header again, now with the label column
     writeln!(
         writer,
-        "ID\tLength\tKmers\tClassification\t{}\tLabel",
-        singleton_kmers
-            .fasta_files
-            .iter()
-            .map(|count| count.to_string())
-            .collect::<Vec<_>>()
-            .join("\t")
+        "# min_kmers={} min_score={} min_minor_score={} min_lod={} prefix_length={}",
+        rules.min_kmers, rules.min_score, rules.min_minor_score, rules.min_lod, rules.prefix_length
     )
     .unwrap();
+    // Write the header again, now with the label column
+    writeln!(writer, "{}\tLabel", header).unwrap();
 
     for read in all_reads.iter() {
         writeln!(writer, "{}", read.join("\t"),).expect("Unable to write row");
@@ -99,89 +293,232 @@ pub fn classify(
         all_reads.len(),
         output_path,
     );
-    log::info!(
-        "Filter rules: unique kmer ≧ {}, A unique + B unique ≧ {}%, B unique ≧ {}%",
-        KMER_THRESHOLD,
-        SCORE_THRESHOLD,
-        MINOR_SCORE_THRESHOLD
+    log::info!("Filter rules: {}", rules);
+}
+
+/// Header of the read classification table, with a count column for each
+/// class followed by a classification column for each group level, the LOD
+/// score, and the segments and breakpoints if requested
+fn header<K>(singleton_kmers: &SingletonKmers<K>, args: &ClassifyArgs) -> String {
+    let mut columns = vec![
+        "ID".to_string(),
+        "Length".to_string(),
+        "Kmers".to_string(),
+        "Classification".to_string(),
+    ];
+    columns.extend(singleton_kmers.fasta_files.iter().cloned());
+    columns.extend(
+        singleton_kmers
+            .groups
+            .iter()
+            .map(|level| level.name.clone()),
     );
+    columns.push("LOD".to_string());
+    if args.segments || args.hmm {
+        columns.push("Segments".to_string());
+    }
+    if args.hmm {
+        columns.push("Breakpoints".to_string());
+    }
+    columns.join("\t")
 }
 
 /// Classify one FASTA/FASTQ file
-fn classify_one(
-    singleton_kmers: &SingletonKmers,
-    kmer_to_file: &HashMap<u64, usize>,
+fn classify_one<K: Kmer>(
+    singleton_kmers: &SingletonKmers<K>,
+    kmer_to_file: &KmerIndex<K>,
+    kmer_to_group: &GroupIndex<K>,
+    scoring: &Scoring,
     reads_file: &str,
+    args: &ClassifyArgs,
 ) -> String {
     // Classify the reads
-    let mut reader = parse_fastx_file(reads_file).expect("valid reads file");
     let file_prefix = prefix(reads_file);
     let output_file = file_prefix + ".read_classifications.tsv";
     let mut writer = BufWriter::new(File::create(&output_file).unwrap());
     log::info!("Classifying reads in `{}`", reads_file);
-    writeln!(
-        writer,
-        "ID\tLength\tKmers\tClassification\t{}",
-        singleton_kmers
-            .fasta_files
-            .iter()
-            .map(|count| count.to_string())
-            .collect::<Vec<_>>()
-            .join("\t")
-    )
-    .unwrap();
+    writeln!(writer, "{}", header(singleton_kmers, args)).unwrap();
 
-    // Iterate through the reads
-    let kmer_size = singleton_kmers.kmer_size;
+    // Read batches on another thread while the current batch is classified
+    // on all threads, writing the results in the order of the reads
     let mut count = 0;
-    while let Some(record) = reader.next() {
-        let record = record.expect("valid record");
-        let seq = record.normalize(false);
-        let mut counts = vec![0; singleton_kmers.n()];
-        for (_, kmer, _) in seq.bit_kmers(kmer_size, true) {
-            if let Some(file_index) = kmer_to_file.get(&kmer.0) {
-                counts[*file_index] += 1;
+    std::thread::scope(|scope| {
+        let (sender, receiver) = sync_channel::<Vec<(String, Vec<u8>)>>(2);
+        scope.spawn(move || {
+            let mut batch = Vec::new();
+            let mut batch_bases = 0;
+            for_each_read(reads_file, args.min_read_quality, |id, seq| {
+                batch.push((id.to_string(), seq.to_vec()));
+                batch_bases += seq.len();
+                if batch.len() >= BATCH_READS || batch_bases >= BATCH_BASES {
+                    sender.send(std::mem::take(&mut batch)).unwrap();
+                    batch_bases = 0;
+                }
+            });
+            if !batch.is_empty() {
+                sender.send(batch).unwrap();
+            }
+        });
+        for batch in receiver {
+            let lines = batch
+                .par_iter()
+                .map(|(id, seq)| {
+                    classify_read(
+                        singleton_kmers,
+                        kmer_to_file,
+                        kmer_to_group,
+                        scoring,
+                        id,
+                        seq,
+                        args,
+                    )
+                })
+                .collect::<Vec<_>>();
+            for line in lines.iter() {
+                writeln!(writer, "{}", line).unwrap();
+            }
+            count += lines.len();
+        }
+    });
+    log::info!("Wrote {} read classifications to `{}`", count, output_file);
+
+    output_file
+}
+
+/// Classify one read, returning its line in the read classifications
+fn classify_read<K: Kmer>(
+    singleton_kmers: &SingletonKmers<K>,
+    kmer_to_file: &KmerIndex<K>,
+    kmer_to_group: &GroupIndex<K>,
+    scoring: &Scoring,
+    id: &str,
+    seq: &[u8],
+    args: &ClassifyArgs,
+) -> String {
+    let kmer_size = singleton_kmers.kmer_size;
+    let sampling = singleton_kmers.sampling;
+    let mut counts = vec![0; singleton_kmers.n()];
+    // Positions of the unique kmers along the read, if segmenting
+    let mut hits = Vec::new();
+    let mut group_counts = singleton_kmers
+        .groups
+        .iter()
+        .map(|level| vec![0; level.groups.len()])
+        .collect::<Vec<_>>();
+    // Kmers looked up, which are the trials of the LOD score
+    let mut n_kmers = 0;
+    for_each_sampled_kmer(seq, kmer_size, sampling, |pos, kmer: K| {
+        n_kmers += 1;
+        if let Some(file_index) = kmer_to_file.get(kmer) {
+            counts[file_index] += 1;
+            if args.segments || args.hmm {
+                hits.push((pos, file_index));
+            }
+        } else if !kmer_to_group.is_empty() {
+            if let Some((level, group)) = kmer_to_group.get(kmer) {
+                group_counts[level][group] += 1;
             }
         }
-        // Get the first part of the ID
-        let id = String::from_utf8(record.id().to_vec())
-            .unwrap()
-            .split_whitespace()
-            .next()
-            .unwrap()
-            .to_string();
-        let results = ClassifyResults {
-            id,
-            seq_len: record.seq().len(),
+    });
+    let results = ClassifyResults {
+        id: id.to_string(),
+        seq_len: seq.len(),
+        counts,
+    };
+    let tag = results.tag(&singleton_kmers.fasta_files, args.min_class_score);
+    let mut to_write = format!(
+        "{}\t{}\t{}\t{}\t{}",
+        results.id,
+        results.seq_len,
+        results.counts.iter().sum::<i32>(),
+        tag,
+        results
+            .counts
+            .iter()
+            .map(|count| count.to_string())
+            .collect::<Vec<_>>()
+            .join("\t")
+    );
+    // Classify again at each group level
+    let level_counts = kmer_to_group.counts(&results.counts, &group_counts);
+    for (level, counts) in singleton_kmers.groups.iter().zip(level_counts) {
+        let level_results = ClassifyResults {
+            id: results.id.clone(),
+            seq_len: results.seq_len,
             counts,
         };
-        let tag = results.tag(&singleton_kmers.fasta_files);
-        let to_write = format!(
-            "{}\t{}\t{}\t{}\t{}",
-            results.id,
-            results.seq_len,
-            results.counts.iter().sum::<i32>(),
-            tag,
-            results
-                .counts
-                .iter()
-                .map(|count| count.to_string())
-                .collect::<Vec<_>>()
-                .join("\t")
-        );
-        writeln!(writer, "{}", to_write).unwrap();
-        count += 1;
+        to_write.push('\t');
+        to_write.push_str(&level_results.tag(&level.groups, args.min_class_score));
     }
-    log::info!("Wrote {} read classifications to `{}`", count, output_file);
+    let lod = results.lod(args.error_prob, scoring.density, n_kmers);
+    to_write.push_str(&format!("\t{:.1}", lod));
+    if let Some(hmm) = &scoring.hmm {
+        let (segments, breakpoints) = hmm.decode(&hits, kmer_size as usize);
+        to_write.push('\t');
+        to_write.push_str(&format_segments(&segments, &singleton_kmers.fasta_files));
+        to_write.push('\t');
+        to_write.push_str(&format_breakpoints(&breakpoints));
+    } else if args.segments {
+        let segments = segment(&hits, kmer_size as usize, args.min_segment_kmers);
+        to_write.push('\t');
+        to_write.push_str(&format_segments(&segments, &singleton_kmers.fasta_files));
+    }
+    to_write
+}
 
-    output_file
+/// Run-length segmentation of the unique kmers along a read into blocks of
+/// one class. Runs with fewer than `min_kmers` non-overlapping kmers are
+/// dropped as noise before merging neighboring blocks of the same class, since
+/// a single variant makes up to `kmer_size` overlapping kmers unique.
+fn segment(hits: &[(usize, usize)], kmer_size: usize, min_kmers: usize) -> Vec<Segment> {
+    let mut runs: Vec<Segment> = Vec::new();
+    // Kmers starting before this position overlap the last counted kmer
+    let mut next = 0;
+    for &(pos, class) in hits.iter() {
+        match runs.last_mut() {
+            Some(run) if run.class == class => {
+                run.end = pos + kmer_size;
+                if pos >= next {
+                    run.kmers += 1;
+                    next = pos + kmer_size;
+                }
+            }
+            _ => {
+                runs.push(Segment {
+                    class,
+                    start: pos,
+                    end: pos + kmer_size,
+                    kmers: 1,
+                });
+                next = pos + kmer_size;
+            }
+        }
+    }
+    let mut segments: Vec<Segment> = Vec::new();
+    for run in runs.into_iter().filter(|run| run.kmers >= min_kmers) {
+        match segments.last_mut() {
+            Some(segment) if segment.class == run.class => {
+                segment.end = run.end;
+                segment.kmers += run.kmers;
+            }
+            _ => segments.push(run),
+        }
+    }
+    segments
 }
 
 /// Main read filtering logic
-fn filter_reads(rc: &str, prefix_length: usize) -> Vec<ReadClassification> {
+fn filter_reads(
+    rc: &str,
+    rules: &FilterRules,
+    lod_column: Option<usize>,
+) -> Vec<ReadClassification> {
+    let prefix_length = rules.prefix_length;
     let file = File::open(rc).expect("Unable to open file");
     let reader = BufReader::new(file);
     let mut filtered = Vec::new();
+    // Names shorter than the prefix never share it
+    let same_prefix = |a: &str, b: &str| matches!((a.get(..prefix_length), b.get(..prefix_length)), (Some(x), Some(y)) if x == y);
 
     for line in reader.lines() {
         let line = line.expect("Unable to read line");
@@ -191,6 +528,7 @@ fn filter_reads(rc: &str, prefix_length: usize) -> Vec<ReadClassification> {
         }
 
         let kmers: i32 = row[2].parse().unwrap_or(0);
+        let lod: f64 = lod_column.map_or(0.0, |i| row[i].parse().unwrap_or(0.0));
         let classification = &row[3];
 
         if classification.contains("Unclassified") {
@@ -198,31 +536,134 @@ fn filter_reads(rc: &str, prefix_length: usize) -> Vec<ReadClassification> {
         }
 
         let parts: Vec<&str> = classification.splitn(2, ':').collect();
-        let ab: Vec<&str> = parts[0].split(',').collect();
-
-        if ab.len() < 2 || ab[0][..prefix_length] != ab[1][..prefix_length] {
+        let names: Vec<&str> = parts[0].split(',').collect();
+        if names.len() < 2 || !same_prefix(names[0], names[1]) {
             continue;
         }
-
-        let (a, b) = if ab[0] < ab[1] {
-            (ab[0], ab[1])
-        } else {
-            (ab[1], ab[0])
-        };
         let scores: Vec<i32> = parts[1]
             .split(',')
             .map(|s| s.parse().unwrap_or(0))
             .collect();
 
+        // Classes beyond the best two count if they are frequent enough and
+        // share the prefix, labeling the read as a multi-way chimera
+        let mut classes = names
+            .iter()
+            .zip(scores.iter())
+            .enumerate()
+            .filter(|&(i, (name, &score))| {
+                i < 2 || (score >= rules.min_minor_score && same_prefix(name, names[0]))
+            })
+            .map(|(_, class)| class)
+            .collect::<Vec<_>>();
+        let total: i32 = classes.iter().map(|&(_, &score)| score).sum();
+        classes.sort();
+
         let mut new_row = row.clone();
-        if kmers >= KMER_THRESHOLD
-            && scores.iter().sum::<i32>() >= SCORE_THRESHOLD
-            && scores[1] >= MINOR_SCORE_THRESHOLD
+        if kmers >= rules.min_kmers
+            && total >= rules.min_score
+            && scores[1] >= rules.min_minor_score
+            && lod >= rules.min_lod
         {
-            new_row.push(format!("{}_{}", a, b));
+            let label = classes.iter().map(|&(&name, _)| name);
+            new_row.push(label.collect::<Vec<_>>().join("_"));
             filtered.push(new_row);
         }
     }
     log::info!("Filtered {} reads from `{}`", filtered.len(), rc);
     filtered
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::hits;
+    use std::io::Write;
+
+    fn spans(segments: &[Segment]) -> Vec<(usize, usize, usize)> {
+        segments.iter().map(|x| (x.class, x.start, x.end)).collect()
+    }
+
+    #[test]
+    fn test_segment() {
+        let segments = segment(&hits(&[(0, 1000, 0), (1000, 2000, 1)]), 24, 10);
+        assert_eq!(spans(&segments), [(0, 0, 1023), (1, 1000, 2023)]);
+        // 1000 overlapping kmers hold 42 non-overlapping ones
+        assert_eq!(segments[0].kmers, 42);
+    }
+
+    #[test]
+    fn test_segment_noise() {
+        // The overlapping kmers of one variant are dropped, and the blocks
+        // around them merged
+        let segments = segment(
+            &hits(&[(0, 1000, 0), (1000, 1024, 1), (1024, 2000, 0)]),
+            24,
+            10,
+        );
+        assert_eq!(spans(&segments), [(0, 0, 2023)]);
+        assert!(segment(&[], 24, 10).is_empty());
+    }
+
+    fn rules() -> FilterRules {
+        FilterRules {
+            prefix_length: 7,
+            min_kmers: KMER_THRESHOLD,
+            min_score: SCORE_THRESHOLD,
+            min_minor_score: MINOR_SCORE_THRESHOLD,
+            min_lod: 0.0,
+        }
+    }
+
+    /// Filter read classifications with the given rows, returning the IDs
+    /// and labels of the reads kept
+    fn filter_rows(rows: &[&str], rules: &FilterRules) -> Vec<(String, String)> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "ID\tLength\tKmers\tClassification\tLOD").unwrap();
+        for row in rows.iter() {
+            writeln!(file, "{}", row).unwrap();
+        }
+        filter_reads(file.path().to_str().unwrap(), rules, Some(4))
+            .into_iter()
+            .map(|row| (row[0].clone(), row.last().unwrap().clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_filter_reads() {
+        let rows = [
+            "r1\t20000\t1000\tSoChr01B,SoChr01A:60,39\t50.0",
+            "r2\t20000\t1000\tUnclassified:40,30\t0.0",
+            "r3\t20000\t100\tSoChr01A,SoChr01B:60,39\t50.0",
+            "r4\t20000\t1000\tSoChr01A,SoChr02B:60,39\t50.0",
+            "r5\t20000\t1000\tSoChr01A,SoChr01B:95,4\t1.0",
+            "r6\t20000\t1000\tSoChr01A,SoChr01B,SoChr01C:50,30,19\t80.0",
+        ];
+        assert_eq!(
+            filter_rows(&rows, &rules()),
+            [
+                ("r1".to_string(), "SoChr01A_SoChr01B".to_string()),
+                ("r6".to_string(), "SoChr01A_SoChr01B_SoChr01C".to_string()),
+            ]
+        );
+        let rules = FilterRules {
+            min_lod: 60.0,
+            ..rules()
+        };
+        assert_eq!(filter_rows(&rows, &rules).len(), 1);
+    }
+
+    #[test]
+    fn test_filter_reads_short_names() {
+        // Names shorter than the prefix, or cut within a character, never match
+        let rows = [
+            "r1\t20000\t1000\tA,B:60,39\t50.0",
+            "r2\t20000\t1000\tChr01é,Chr01e:60,39\t50.0",
+        ];
+        let rules = FilterRules {
+            prefix_length: 6,
+            ..rules()
+        };
+        assert!(filter_rows(&rows, &rules).is_empty());
+    }
+}
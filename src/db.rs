@@ -0,0 +1,376 @@
+use crate::index::{
+    index_kmer_size, is_kmer_index, mapped_checksum, open_kmer_index, GroupIndex, KmerIndex,
+};
+use crate::kmer::{assert_kmer_width, Kmer};
+use crate::models::{DbHeader, SharedKmers, SingletonKmers};
+
+use bincode::{serialize_into, Options};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// Magic bytes at the start of a bincode kmer database
+pub const DB_MAGIC: &[u8; 8] = b"KLSFYKDB";
+/// Magic bytes at the start of the shared kmers kept next to a database
+pub const SHARED_MAGIC: &[u8; 8] = b"KLSFYSHR";
+
+/// Version of the kmer database format
+pub const DB_VERSION: u32 = 1;
+
+/// Layout of databases written without a header
+#[derive(Deserialize)]
+struct LegacySingletonKmers<K> {
+    kmer_size: u8,
+    fasta_files: Vec<String>,
+    kmers: Vec<Vec<K>>,
+}
+
+/// Pass-through reader or writer that computes the CRC32 of the bytes
+struct Crc32<T> {
+    inner: T,
+    hasher: crc32fast::Hasher,
+}
+
+impl<T> Crc32<T> {
+    fn new(inner: T) -> Self {
+        Crc32 {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for Crc32<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Crc32<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Bincode options of `bincode::deserialize_from`, but reading at most `limit`
+/// bytes so that corrupt lengths fail cleanly instead of exhausting memory
+fn bincode_options(limit: u64) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+}
+
+/// Write the singleton kmers behind a header with the magic bytes, format
+/// version, build parameters and the checksum of the kmers
+pub fn write_kmer_db<K: Kmer>(bincode_file: &str, singleton_kmers: &SingletonKmers<K>) {
+    // Dry run to checksum the payload, since the header goes first
+    let mut crc = Crc32::new(std::io::sink());
+    serialize_into(&mut crc, singleton_kmers).expect("serialization to succeed");
+    let header = DbHeader {
+        version: DB_VERSION,
+        checksum: crc.hasher.finalize(),
+        ..singleton_kmers.header.clone()
+    };
+    let mut writer = BufWriter::new(File::create(bincode_file).unwrap());
+    writer.write_all(DB_MAGIC).unwrap();
+    writer.write_all(&DB_VERSION.to_le_bytes()).unwrap();
+    serialize_into(&mut writer, &header).expect("serialization to succeed");
+    serialize_into(&mut writer, singleton_kmers).expect("serialization to succeed");
+    writer.flush().unwrap();
+}
+
+/// Read and validate the header of a database written by `write_kmer_db`, past
+/// the magic bytes
+fn read_kmer_db_header<R: Read>(mut reader: R, bincode_file: &str, limit: u64) -> DbHeader {
+    let mut version = [0u8; 4];
+    reader
+        .read_exact(&mut version)
+        .unwrap_or_else(|_| panic!("`{}` is truncated", bincode_file));
+    let version = u32::from_le_bytes(version);
+    assert!(
+        version == DB_VERSION,
+        "`{}` has unsupported format version {} (expected {}), please rebuild it",
+        bincode_file,
+        version,
+        DB_VERSION
+    );
+    let mut header: DbHeader = bincode_options(limit)
+        .deserialize_from(&mut reader)
+        .unwrap_or_else(|e| panic!("`{}` has a corrupt header: {}", bincode_file, e));
+    header.version = version;
+    header
+}
+
+/// Read and validate a database written by `write_kmer_db`, past the magic bytes
+fn read_kmer_db<K: Kmer, R: Read>(
+    mut reader: R,
+    bincode_file: &str,
+    limit: u64,
+) -> SingletonKmers<K> {
+    let header = read_kmer_db_header(&mut reader, bincode_file, limit);
+    assert_kmer_width::<K>(header.kmer_size, bincode_file);
+    let mut crc = Crc32::new(reader);
+    let mut singleton_kmers: SingletonKmers<K> = bincode_options(limit)
+        .deserialize_from(&mut crc)
+        .unwrap_or_else(|e| panic!("`{}` is truncated or corrupt: {}", bincode_file, e));
+    let checksum = crc.hasher.finalize();
+    assert!(
+        checksum == header.checksum,
+        "`{}` failed the integrity check (checksum {:08x}, expected {:08x})",
+        bincode_file,
+        checksum,
+        header.checksum
+    );
+    assert!(
+        header.kmer_size == singleton_kmers.kmer_size,
+        "`{}` has inconsistent kmer sizes ({} in header, {} in kmers)",
+        bincode_file,
+        header.kmer_size,
+        singleton_kmers.kmer_size
+    );
+    singleton_kmers.header = header;
+    singleton_kmers
+}
+
+/// Path of the shared kmers kept next to a database
+pub fn shared_kmers_file(bincode_file: &str) -> String {
+    format!("{}.shared", bincode_file)
+}
+
+/// Write the shared kmers behind the magic bytes and format version
+pub fn write_shared_kmers<K: Kmer>(shared_file: &str, shared_kmers: &SharedKmers<K>) {
+    let mut writer = BufWriter::new(File::create(shared_file).unwrap());
+    writer.write_all(SHARED_MAGIC).unwrap();
+    writer.write_all(&DB_VERSION.to_le_bytes()).unwrap();
+    serialize_into(&mut writer, shared_kmers).expect("serialization to succeed");
+    writer.flush().unwrap();
+}
+
+/// Read the shared kmers written by `write_shared_kmers`
+pub fn read_shared_kmers<K: Kmer>(shared_file: &str) -> SharedKmers<K> {
+    let file = File::open(shared_file).unwrap_or_else(|_| {
+        panic!(
+            "`{}` not found, build with --keep-shared to allow updates and merges",
+            shared_file
+        )
+    });
+    let limit = file.metadata().unwrap().len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 12];
+    reader
+        .read_exact(&mut magic)
+        .unwrap_or_else(|_| panic!("`{}` is truncated", shared_file));
+    assert!(
+        &magic[..8] == SHARED_MAGIC,
+        "`{}` is not a shared kmers file",
+        shared_file
+    );
+    let version = u32::from_le_bytes(magic[8..].try_into().unwrap());
+    assert!(
+        version == DB_VERSION,
+        "`{}` has unsupported format version {} (expected {}), please rebuild it",
+        shared_file,
+        version,
+        DB_VERSION
+    );
+    bincode_options(limit)
+        .deserialize_from(reader)
+        .unwrap_or_else(|e| panic!("`{}` is truncated or corrupt: {}", shared_file, e))
+}
+
+/// Read the kmer size of a database without loading the kmers, to pick the kmer
+/// width to load it with
+pub fn kmer_size_of(bincode_file: &str) -> u8 {
+    if is_kmer_index(bincode_file) {
+        return index_kmer_size(bincode_file);
+    }
+    let file = File::open(bincode_file).unwrap();
+    let limit = file.metadata().unwrap().len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .unwrap_or_else(|_| panic!("`{}` is not a valid kmer database", bincode_file));
+    if &magic == DB_MAGIC {
+        read_kmer_db_header(reader, bincode_file, limit).kmer_size
+    } else {
+        // Databases without a header start with the kmer size
+        magic[0]
+    }
+}
+
+/// Load the bincode file and print some information about it.
+pub fn load_kmer_db<K: Kmer>(bincode_file: &str) -> SingletonKmers<K> {
+    if is_kmer_index(bincode_file) {
+        let (mut singleton_kmers, kmer_to_file, kmer_to_group) =
+            load_kmer_index(bincode_file, true);
+        singleton_kmers.kmers = kmer_to_file.to_kmer_lists(singleton_kmers.n());
+        for (l, level) in singleton_kmers.groups.iter_mut().enumerate() {
+            level.kmers = kmer_to_group.level(l).to_kmer_lists(level.groups.len());
+        }
+        return singleton_kmers;
+    }
+    let file = File::open(bincode_file).unwrap();
+    let limit = file.metadata().unwrap().len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 8];
+    let singleton_kmers = if reader.read_exact(&mut magic).is_ok() && &magic == DB_MAGIC {
+        read_kmer_db(reader, bincode_file, limit)
+    } else {
+        // Databases from earlier versions have no header
+        log::warn!("`{}` has no header, consider rebuilding it", bincode_file);
+        let reader = BufReader::new(File::open(bincode_file).unwrap());
+        assert_kmer_width::<K>(kmer_size_of(bincode_file), bincode_file);
+        let legacy: LegacySingletonKmers<K> = bincode_options(limit)
+            .deserialize_from(reader)
+            .unwrap_or_else(|e| panic!("`{}` is not a valid kmer database: {}", bincode_file, e));
+        SingletonKmers {
+            kmer_size: legacy.kmer_size,
+            fasta_files: legacy.fasta_files,
+            kmers: legacy.kmers,
+            header: DbHeader {
+                kmer_size: legacy.kmer_size,
+                canonical: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    };
+    assert!(
+        singleton_kmers.header.canonical,
+        "`{}` has non-canonical kmers, which are not supported",
+        bincode_file
+    );
+    log::info!(
+        "Loaded singleton kmers (K={}) from `{}`",
+        singleton_kmers.kmer_size,
+        bincode_file
+    );
+    singleton_kmers
+}
+
+/// Create mapping between kmer and the file index
+///
+/// The kmers are moved into the index, so `singleton_kmers.kmers` is left empty.
+pub fn map_kmer_to_file<K: Kmer>(singleton_kmers: &mut SingletonKmers<K>) -> KmerIndex<K> {
+    let kmers = std::mem::take(&mut singleton_kmers.kmers);
+    let kmer_to_file = KmerIndex::new(singleton_kmers.kmer_size, kmers);
+    log::info!(
+        "Mapped {} kmers to files ({} MiB)",
+        kmer_to_file.len(),
+        kmer_to_file.size_in_bytes() >> 20
+    );
+    kmer_to_file
+}
+
+/// Load the kmer metadata and the kmer indices used for lookups, of the classes
+/// and of the group levels
+///
+/// Memory-mapped databases are opened without deserializing the kmers, while
+/// bincode databases are loaded and indexed. `kmers` and the kmers of the
+/// group levels are left empty either way. Bincode databases are always
+/// verified while loading; `verify` also checksums a memory-mapped index,
+/// which reads the whole file.
+pub fn load_kmer_index<K: Kmer>(
+    bincode_file: &str,
+    verify: bool,
+) -> (SingletonKmers<K>, KmerIndex<K>, GroupIndex<K>) {
+    if !is_kmer_index(bincode_file) {
+        let mut singleton_kmers = load_kmer_db(bincode_file);
+        let kmer_to_file = map_kmer_to_file(&mut singleton_kmers);
+        let kmer_to_group = GroupIndex::new(singleton_kmers.kmer_size, &mut singleton_kmers.groups);
+        return (singleton_kmers, kmer_to_file, kmer_to_group);
+    }
+    let (singleton_kmers, kmer_to_file, kmer_to_group) = open_kmer_index(bincode_file);
+    if verify {
+        let checksum = mapped_checksum(&singleton_kmers, &kmer_to_file, &kmer_to_group);
+        assert!(
+            checksum == singleton_kmers.header.checksum,
+            "`{}` failed the integrity check (checksum {:08x}, expected {:08x})",
+            bincode_file,
+            checksum,
+            singleton_kmers.header.checksum
+        );
+    }
+    log::info!(
+        "Mapped singleton kmers (K={}) from `{}`",
+        singleton_kmers.kmer_size,
+        bincode_file
+    );
+    (singleton_kmers, kmer_to_file, kmer_to_group)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::sample_db;
+    use tempfile::NamedTempFile;
+
+    /// Write the sample database, returning its file
+    fn write_sample_db() -> NamedTempFile {
+        let bincode_file = NamedTempFile::new().unwrap();
+        write_kmer_db(bincode_file.path().to_str().unwrap(), &sample_db::<u64>(21));
+        bincode_file
+    }
+
+    #[test]
+    fn test_kmer_db() {
+        let bincode_file = write_sample_db();
+        let path = bincode_file.path().to_str().unwrap();
+        assert_eq!(kmer_size_of(path), 21);
+        let singleton_kmers = load_kmer_db::<u64>(path);
+        let expected = sample_db::<u64>(21);
+        let header = &singleton_kmers.header;
+        assert_eq!(header.version, DB_VERSION);
+        assert_eq!(header.params, expected.header.params);
+        assert_eq!(singleton_kmers.fasta_files, expected.fasta_files);
+        assert_eq!(singleton_kmers.kmers, expected.kmers);
+        for (level, expected_level) in singleton_kmers.groups.iter().zip(expected.groups.iter()) {
+            assert_eq!(level.groups, expected_level.groups);
+            assert_eq!(level.members, expected_level.members);
+            assert_eq!(level.kmers, expected_level.kmers);
+        }
+    }
+
+    #[test]
+    fn test_kmer_db_u128() {
+        let bincode_file = NamedTempFile::new().unwrap();
+        let bincode_file = bincode_file.path().to_str().unwrap();
+        let expected = sample_db::<u128>(41);
+        write_kmer_db(bincode_file, &expected);
+        assert_eq!(kmer_size_of(bincode_file), 41);
+        assert_eq!(load_kmer_db::<u128>(bincode_file).kmers, expected.kmers);
+    }
+
+    #[test]
+    #[should_panic(expected = "is truncated or corrupt")]
+    fn test_kmer_db_truncated() {
+        let bincode_file = write_sample_db();
+        let len = bincode_file.as_file().metadata().unwrap().len();
+        bincode_file.as_file().set_len(len - 10).unwrap();
+        load_kmer_db::<u64>(bincode_file.path().to_str().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "failed the integrity check")]
+    fn test_kmer_db_checksum() {
+        let bincode_file = write_sample_db();
+        let path = bincode_file.path().to_str().unwrap();
+        // Flip a bit of one kmer, which still deserializes
+        let mut bytes = std::fs::read(path).unwrap();
+        let kmer = sample_db::<u64>(21).kmers[1][0].to_le_bytes();
+        let pos = bytes.windows(8).position(|x| x == kmer).unwrap();
+        bytes[pos] ^= 1;
+        std::fs::write(path, bytes).unwrap();
+        load_kmer_db::<u64>(path);
+    }
+}
@@ -1,9 +1,9 @@
-use crate::models::prefix;
+use crate::models::{assert_stdin_once, prefix};
+use crate::reads::for_each_read;
 
 use clap::Parser;
 use csv::ReaderBuilder;
 use log;
-use needletail::{parse_fastx_file, Sequence};
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::io::Write;
@@ -12,7 +12,7 @@ use std::io::Write;
 pub struct ExtractArgs {
     /// Filtered reads TSV file
     pub reads_tsv: String,
-    /// FASTA files to extract kmers
+    /// FASTA/FASTQ or unaligned BAM/CRAM files to extract reads from
     pub fasta_files: Vec<String>,
     /// Output file
     #[clap(short, long)]
@@ -25,6 +25,7 @@ fn get_read_ids(reads_tsv: &str) -> HashMap<String, String> {
     let reader = ReaderBuilder::new()
         .delimiter(b'\t')
         .has_headers(true)
+        .comment(Some(b'#'))
         .from_path(reads_tsv)
         .expect("valid TSV file");
     for result in reader.into_records() {
@@ -40,23 +41,14 @@ fn get_read_ids(reads_tsv: &str) -> HashMap<String, String> {
     read_map
 }
 
-/// Extract reads from a single FASTA/FASTQ file
+/// Extract reads from a single FASTA/FASTQ or BAM/CRAM file
 fn extract_one(read_map: &HashMap<String, String>, fasta_file: &str) -> String {
-    let mut reader = parse_fastx_file(fasta_file).expect("valid reads file");
     let file_prefix = prefix(fasta_file);
     let output_file = file_prefix.to_string() + ".extracted.fasta";
     let mut writer = std::fs::File::create(&output_file).unwrap();
     let mut total = 0;
-    while let Some(record) = reader.next() {
-        let record = record.expect("valid record");
-        let seq = record.normalize(false);
-        let id = String::from_utf8(record.id().to_vec())
-            .unwrap()
-            .split_whitespace()
-            .next()
-            .unwrap()
-            .to_string();
-        if let Some(new_read_id) = read_map.get(&id) {
+    for_each_read(fasta_file, None, |id, seq| {
+        if let Some(new_read_id) = read_map.get(id) {
             writeln!(
                 writer,
                 ">{}\n{}",
@@ -66,13 +58,14 @@ fn extract_one(read_map: &HashMap<String, String>, fasta_file: &str) -> String {
             .unwrap();
             total += 1;
         }
-    }
+    });
     log::info!("Extracted {} reads from `{}`", total, fasta_file);
     output_file
 }
 
 /// Extract reads from FASTA/FASTQ files
 pub fn extract(reads_tsv: &str, fasta_files: &Vec<String>, output_file: &str) {
+    assert_stdin_once(fasta_files);
     let read_map = get_read_ids(reads_tsv);
     let output_files = fasta_files
         .par_iter()
@@ -81,7 +74,7 @@ pub fn extract(reads_tsv: &str, fasta_files: &Vec<String>, output_file: &str) {
     // Merge the output files
     let mut writer = std::fs::File::create(output_file).unwrap();
     for output_file in output_files.iter() {
-        let mut reader = std::fs::File::open(&output_file).unwrap();
+        let mut reader = std::fs::File::open(output_file).unwrap();
         std::io::copy(&mut reader, &mut writer).expect("valid copy");
     }
     // Cleanup the output files
@@ -35,7 +35,7 @@ pub fn extract_bam(region_file: &str, bam_file: &str, flank_size: i32) {
         let end = region[1].parse::<i32>().expect("valid end position");
         let start = (start - flank_size).max(0);
         let end = end + flank_size;
-        let _ = bam.fetch((chrom, start, end)).expect("valid region");
+        bam.fetch((chrom, start, end)).expect("valid region");
         for record in bam.records() {
             let record = record.expect("valid record");
             if record.is_unmapped()
@@ -0,0 +1,383 @@
+use crate::models::Segment;
+
+/// Default probability of switching class between consecutive unique kmers
+pub const SWITCH_PROB: f64 = 1e-4;
+/// Default probability that a unique kmer comes from another class than the
+/// segment it lies in, e.g. from sequencing errors or gene conversion
+pub const ERROR_PROB: f64 = 0.01;
+/// Mass of the posterior breakpoint confidence intervals
+const CONFIDENCE: f64 = 0.95;
+
+/// Hidden Markov model of a read as blocks of classes. The states are the
+/// classes and each observation is the class of a unique kmer along the read.
+/// Kmers without a unique class are equally likely in all states, so they
+/// only add a constant and are left out.
+pub struct Hmm {
+    switch_prob: f64,
+    log_hit: f64,
+    log_error: f64,
+}
+
+/// Switch between consecutive segments, with the posterior confidence
+/// interval of its position
+pub struct Breakpoint {
+    pub pos: usize,
+    pub low: usize,
+    pub high: usize,
+}
+
+impl Hmm {
+    pub fn new(n_classes: usize, switch_prob: f64, error_prob: f64) -> Self {
+        assert!(
+            switch_prob > 0.0 && switch_prob < 0.5,
+            "switch probability must be between 0 and 0.5"
+        );
+        assert!(
+            error_prob > 0.0 && error_prob < 0.5,
+            "error probability must be between 0 and 0.5"
+        );
+        Hmm {
+            switch_prob,
+            log_hit: (1.0 - error_prob).ln(),
+            log_error: (error_prob / (n_classes.max(2) - 1) as f64).ln(),
+        }
+    }
+
+    /// Decode the (position, class) of the unique kmers along a read into
+    /// segments with Viterbi, and the breakpoints between them
+    pub fn decode(
+        &self,
+        hits: &[(usize, usize)],
+        kmer_size: usize,
+    ) -> (Vec<Segment>, Vec<Breakpoint>) {
+        if hits.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+        let obs_hits = collapse_hits(hits, kmer_size);
+        // Only the classes seen in the read can explain it
+        let mut states = obs_hits.iter().map(|x| x.class).collect::<Vec<_>>();
+        states.sort_unstable();
+        states.dedup();
+        let obs = obs_hits
+            .iter()
+            .map(|x| states.binary_search(&x.class).unwrap())
+            .collect::<Vec<_>>();
+        let m = states.len();
+        let (log_stay, log_switch) = if m > 1 {
+            (
+                (1.0 - self.switch_prob).ln(),
+                (self.switch_prob / (m - 1) as f64).ln(),
+            )
+        } else {
+            (0.0, f64::NEG_INFINITY)
+        };
+        let emission = |t: usize, s: usize| {
+            if obs[t] == s {
+                self.log_hit
+            } else {
+                self.log_error
+            }
+        };
+        let transition = |from: usize, to: usize| if from == to { log_stay } else { log_switch };
+
+        let path = viterbi(obs.len(), m, emission, log_stay, log_switch);
+        let mut segments: Vec<Segment> = Vec::new();
+        // Index of the last observation of each segment but the last
+        let mut switches = Vec::new();
+        for (t, &s) in path.iter().enumerate() {
+            let (hit, class) = (&obs_hits[t], states[s]);
+            match segments.last_mut() {
+                Some(segment) if segment.class == class => {
+                    segment.end = hit.last + kmer_size;
+                    segment.kmers += (hit.class == class) as usize;
+                }
+                _ => {
+                    if t > 0 {
+                        switches.push(t - 1);
+                    }
+                    segments.push(Segment {
+                        class,
+                        start: hit.first,
+                        end: hit.last + kmer_size,
+                        kmers: (hit.class == class) as usize,
+                    });
+                }
+            }
+        }
+        if switches.is_empty() {
+            return (segments, Vec::new());
+        }
+
+        // Posterior of the switch position between the neighboring switches
+        let (alpha, beta, log_z) = forward_backward(obs.len(), m, emission, transition);
+        let breakpoints = switches
+            .iter()
+            .enumerate()
+            .map(|(i, &t)| {
+                let (a, b) = (path[t], path[t + 1]);
+                let first = if i == 0 { 0 } else { switches[i - 1] + 1 };
+                let last = switches.get(i + 1).copied().unwrap_or(obs.len() - 1);
+                let weights = (first..last)
+                    .map(|j| {
+                        (alpha[j][a] + log_switch + emission(j + 1, b) + beta[j + 1][b] - log_z)
+                            .exp()
+                    })
+                    .collect::<Vec<_>>();
+                let (low, high) = confidence_interval(&weights);
+                // A switch after observation j lies between the variant of its
+                // last kmer and that of the first kmer of the next observation
+                let low_end = |j: usize| obs_hits[j].last;
+                let high_end = |j: usize| obs_hits[j + 1].first + kmer_size;
+                Breakpoint {
+                    pos: (low_end(t) + high_end(t)) / 2,
+                    low: low_end((first + low).min(t)),
+                    high: high_end((first + high).max(t)),
+                }
+            })
+            .collect();
+        (segments, breakpoints)
+    }
+}
+
+/// Observation of a unique kmer class, standing for the kmers of that class
+/// starting within `kmer_size` bases of the first one, which overlap it
+struct ObservedHit {
+    class: usize,
+    first: usize,
+    last: usize,
+}
+
+/// Collapse the (position, class) of overlapping unique kmers of one class
+/// into one observation. Overlapping kmers share bases, so a single variant
+/// makes up to `kmer_size` kmers unique; counting them as independent
+/// observations would overweight it.
+fn collapse_hits(hits: &[(usize, usize)], kmer_size: usize) -> Vec<ObservedHit> {
+    let mut obs_hits: Vec<ObservedHit> = Vec::new();
+    for &(pos, class) in hits.iter() {
+        match obs_hits.last_mut() {
+            Some(hit) if hit.class == class && pos < hit.first + kmer_size => hit.last = pos,
+            _ => obs_hits.push(ObservedHit {
+                class,
+                first: pos,
+                last: pos,
+            }),
+        }
+    }
+    obs_hits
+}
+
+/// Central interval holding `CONFIDENCE` of the posterior mass of the switch
+/// positions, as the first and last index into `weights`
+fn confidence_interval(weights: &[f64]) -> (usize, usize) {
+    let total: f64 = weights.iter().sum();
+    let tail = (1.0 - CONFIDENCE) / 2.0 * total;
+    let mut cumulative = 0.0;
+    let (mut low, mut high) = (0, weights.len() - 1);
+    let mut found_low = false;
+    for (j, weight) in weights.iter().enumerate() {
+        cumulative += weight;
+        if !found_low && cumulative > tail {
+            low = j;
+            found_low = true;
+        }
+        if cumulative >= total - tail {
+            high = j;
+            break;
+        }
+    }
+    (low, high)
+}
+
+/// Most likely state path, with uniform start and switch probabilities
+fn viterbi(
+    n: usize,
+    m: usize,
+    emission: impl Fn(usize, usize) -> f64,
+    log_stay: f64,
+    log_switch: f64,
+) -> Vec<usize> {
+    let mut scores = (0..m).map(|s| emission(0, s)).collect::<Vec<_>>();
+    let mut back = vec![0; n * m];
+    for t in 1..n {
+        let (best, best_score) = argmax(&scores);
+        scores = (0..m)
+            .map(|s| {
+                let stay = scores[s] + log_stay;
+                let switch = best_score + log_switch;
+                if best == s || stay >= switch {
+                    back[t * m + s] = s;
+                    stay + emission(t, s)
+                } else {
+                    back[t * m + s] = best;
+                    switch + emission(t, s)
+                }
+            })
+            .collect();
+    }
+    let mut path = vec![argmax(&scores).0; n];
+    for t in (1..n).rev() {
+        path[t - 1] = back[t * m + path[t]];
+    }
+    path
+}
+
+/// Log forward and backward probabilities, and the log likelihood
+fn forward_backward(
+    n: usize,
+    m: usize,
+    emission: impl Fn(usize, usize) -> f64,
+    transition: impl Fn(usize, usize) -> f64,
+) -> (Vec<Vec<f64>>, Vec<Vec<f64>>, f64) {
+    let log_start = -(m as f64).ln();
+    let mut alpha = vec![vec![0.0; m]; n];
+    alpha[0] = (0..m).map(|s| log_start + emission(0, s)).collect();
+    for t in 1..n {
+        for s in 0..m {
+            let terms = (0..m)
+                .map(|r| alpha[t - 1][r] + transition(r, s))
+                .collect::<Vec<_>>();
+            alpha[t][s] = log_sum_exp(&terms) + emission(t, s);
+        }
+    }
+    let mut beta = vec![vec![0.0; m]; n];
+    for t in (0..n - 1).rev() {
+        for s in 0..m {
+            let terms = (0..m)
+                .map(|r| transition(s, r) + emission(t + 1, r) + beta[t + 1][r])
+                .collect::<Vec<_>>();
+            beta[t][s] = log_sum_exp(&terms);
+        }
+    }
+    let log_z = log_sum_exp(&alpha[n - 1]);
+    (alpha, beta, log_z)
+}
+
+fn argmax(values: &[f64]) -> (usize, f64) {
+    values
+        .iter()
+        .copied()
+        .enumerate()
+        .fold((0, f64::NEG_INFINITY), |best, (i, x)| {
+            if x > best.1 {
+                (i, x)
+            } else {
+                best
+            }
+        })
+}
+
+fn log_sum_exp(values: &[f64]) -> f64 {
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return max;
+    }
+    max + values.iter().map(|x| (x - max).exp()).sum::<f64>().ln()
+}
+
+/// Format breakpoints as `12050(11980-12120)`, or `-` if there are none
+pub fn format_breakpoints(breakpoints: &[Breakpoint]) -> String {
+    if breakpoints.is_empty() {
+        return "-".to_string();
+    }
+    breakpoints
+        .iter()
+        .map(|x| format!("{}({}-{})", x.pos, x.low, x.high))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::hits;
+
+    #[test]
+    fn test_viterbi() {
+        let hmm = Hmm::new(2, SWITCH_PROB, ERROR_PROB);
+        let (log_stay, log_switch) = ((1.0 - SWITCH_PROB).ln(), SWITCH_PROB.ln());
+        let decode = |obs: &[usize]| {
+            let emission = |t: usize, s: usize| {
+                if obs[t] == s {
+                    hmm.log_hit
+                } else {
+                    hmm.log_error
+                }
+            };
+            viterbi(obs.len(), 2, emission, log_stay, log_switch)
+        };
+        let obs = [0, 0, 0, 0, 0, 1, 1, 1, 1, 1];
+        assert_eq!(decode(&obs), obs);
+        // A stray observation is cheaper as an error than as two switches
+        assert_eq!(decode(&[0, 0, 0, 1, 0, 0, 0]), [0; 7]);
+    }
+
+    #[test]
+    fn test_forward_backward() {
+        let hmm = Hmm::new(2, SWITCH_PROB, ERROR_PROB);
+        let obs = [0, 0, 1, 0, 1, 1];
+        let emission = |t: usize, s: usize| {
+            if obs[t] == s {
+                hmm.log_hit
+            } else {
+                hmm.log_error
+            }
+        };
+        let transition = |from: usize, to: usize| {
+            if from == to {
+                (1.0 - SWITCH_PROB).ln()
+            } else {
+                SWITCH_PROB.ln()
+            }
+        };
+        let (alpha, beta, log_z) = forward_backward(obs.len(), 2, emission, transition);
+        // The posteriors of the states sum to one at every position
+        for t in 0..obs.len() {
+            let posterior = log_sum_exp(&[alpha[t][0] + beta[t][0], alpha[t][1] + beta[t][1]]);
+            assert!((posterior - log_z).abs() < 1e-9);
+        }
+        // A single observation is explained by either state with equal odds
+        let (_, _, log_z) = forward_backward(1, 2, emission, transition);
+        let expected = (0.5 * (1.0 - ERROR_PROB) + 0.5 * ERROR_PROB).ln();
+        assert!((log_z - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_confidence_interval() {
+        assert_eq!(confidence_interval(&[0.0, 0.0, 1.0, 0.0]), (2, 2));
+        assert_eq!(confidence_interval(&[1.0; 40]), (1, 38));
+        assert_eq!(confidence_interval(&[1.0]), (0, 0));
+    }
+
+    #[test]
+    fn test_collapse_hits() {
+        let obs_hits = collapse_hits(&hits(&[(0, 30, 0), (30, 40, 1)]), 24);
+        let spans = obs_hits
+            .iter()
+            .map(|x| (x.class, x.first, x.last))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, [(0, 0, 23), (0, 24, 29), (1, 30, 39)]);
+    }
+
+    #[test]
+    fn test_decode() {
+        let hmm = Hmm::new(2, SWITCH_PROB, ERROR_PROB);
+        let (segments, breakpoints) = hmm.decode(&hits(&[(0, 5000, 0), (5000, 10000, 1)]), 24);
+        let spans = segments
+            .iter()
+            .map(|x| (x.class, x.start, x.end))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, [(0, 0, 5023), (1, 5000, 10023)]);
+        assert_eq!(breakpoints.len(), 1);
+        let breakpoint = &breakpoints[0];
+        assert!(breakpoint.low < breakpoint.pos && breakpoint.pos < breakpoint.high);
+        assert!(breakpoint.low <= 5000 && breakpoint.high >= 5000);
+
+        // The overlapping kmers of a single variant do not form a segment
+        let (segments, breakpoints) = hmm.decode(
+            &hits(&[(0, 5000, 0), (5000, 5024, 1), (5024, 10000, 0)]),
+            24,
+        );
+        assert_eq!(segments.len(), 1);
+        assert!(breakpoints.is_empty());
+    }
+}
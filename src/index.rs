@@ -0,0 +1,683 @@
+use crate::kmer::{assert_kmer_width, Kmer};
+use crate::models::{DbHeader, GroupLevel, SingletonKmers};
+
+use bincode::{deserialize, serialize, Options};
+use memmap2::Mmap;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::sync::Arc;
+
+/// Magic bytes at the start of a memory-mapped kmer database
+pub const INDEX_MAGIC: &[u8; 8] = b"KLSFYIDX";
+/// Layout version of the memory-mapped kmer database
+pub const INDEX_VERSION: u32 = 1;
+/// Size of the fixed header, which is followed by the metadata
+const HEADER_SIZE: usize = 48;
+/// Size of the header of each group level, which is followed by its arrays
+const LEVEL_HEADER_SIZE: usize = 24;
+
+/// Backing memory of the kmer index
+enum Storage<K> {
+    Owned {
+        kmers: Vec<K>,
+        file_indices: Vec<u16>,
+        offsets: Vec<u64>,
+    },
+    Mapped {
+        mmap: Arc<Mmap>,
+        n_kmers: usize,
+        n_offsets: usize,
+        offsets_start: usize,
+        kmers_start: usize,
+        file_indices_start: usize,
+    },
+}
+
+/// Sorted kmer table that maps each singleton kmer to its file index.
+///
+/// Kmers are stored in one sorted array alongside a packed array of file
+/// indices (10 bytes per kmer). A small directory of offsets keyed by the
+/// leading bits of the kmer narrows each lookup down to a short binary search.
+/// The same arrays are laid out on disk, so an index can be memory-mapped.
+pub struct KmerIndex<K = u64> {
+    storage: Storage<K>,
+    shift: u32,
+}
+
+impl<K: Kmer> KmerIndex<K> {
+    /// Build the index by merging the per-file kmer lists
+    pub fn new(kmer_size: u8, mut kmers: Vec<Vec<K>>) -> KmerIndex<K> {
+        assert!(
+            kmers.len() <= u16::MAX as usize,
+            "too many files to index ({})",
+            kmers.len()
+        );
+        kmers.iter_mut().for_each(|x| x.sort_unstable());
+        let n = kmers.iter().map(|x| x.len()).sum::<usize>();
+        let mut sorted_kmers = Vec::with_capacity(n);
+        let mut file_indices = Vec::with_capacity(n);
+        let mut heap = kmers
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| !x.is_empty())
+            .map(|(i, x)| Reverse((x[0], i, 0)))
+            .collect::<BinaryHeap<_>>();
+        while let Some(Reverse((kmer, file_index, pos))) = heap.pop() {
+            sorted_kmers.push(kmer);
+            file_indices.push(file_index as u16);
+            if let Some(&next) = kmers[file_index].get(pos + 1) {
+                heap.push(Reverse((next, file_index, pos + 1)));
+            }
+        }
+        drop(kmers);
+
+        // Aim for ~8 kmers per bucket
+        let kmer_bits = 2 * kmer_size as u32;
+        let bucket_bits = (n / 8).max(2).ilog2().min(kmer_bits).min(32);
+        let shift = kmer_bits - bucket_bits;
+        let mut offsets = vec![0u64; (1 << bucket_bits) + 1];
+        for &kmer in sorted_kmers.iter() {
+            offsets[kmer.high_bits(shift) as usize + 1] += 1;
+        }
+        for i in 1..offsets.len() {
+            offsets[i] += offsets[i - 1];
+        }
+        KmerIndex {
+            storage: Storage::Owned {
+                kmers: sorted_kmers,
+                file_indices,
+                offsets,
+            },
+            shift,
+        }
+    }
+
+    #[inline]
+    fn kmers(&self) -> &[K] {
+        match &self.storage {
+            Storage::Owned { kmers, .. } => kmers,
+            Storage::Mapped {
+                mmap,
+                n_kmers,
+                kmers_start,
+                ..
+            } => mapped_slice(mmap, *kmers_start, *n_kmers),
+        }
+    }
+
+    #[inline]
+    fn file_indices(&self) -> &[u16] {
+        match &self.storage {
+            Storage::Owned { file_indices, .. } => file_indices,
+            Storage::Mapped {
+                mmap,
+                n_kmers,
+                file_indices_start,
+                ..
+            } => mapped_slice(mmap, *file_indices_start, *n_kmers),
+        }
+    }
+
+    #[inline]
+    fn offsets(&self) -> &[u64] {
+        match &self.storage {
+            Storage::Owned { offsets, .. } => offsets,
+            Storage::Mapped {
+                mmap,
+                n_offsets,
+                offsets_start,
+                ..
+            } => mapped_slice(mmap, *offsets_start, *n_offsets),
+        }
+    }
+
+    /// Get the file index of a kmer, if it is a singleton kmer
+    #[inline]
+    pub fn get(&self, kmer: K) -> Option<usize> {
+        self.position(kmer).map(|i| self.file_indices()[i] as usize)
+    }
+
+    /// Get the position of a kmer in the sorted kmers of the index
+    #[inline]
+    pub fn position(&self, kmer: K) -> Option<usize> {
+        let offsets = self.offsets();
+        let bucket = kmer.high_bits(self.shift) as usize;
+        if bucket + 1 >= offsets.len() {
+            return None;
+        }
+        let start = offsets[bucket] as usize;
+        let end = offsets[bucket + 1] as usize;
+        self.kmers()[start..end]
+            .binary_search(&kmer)
+            .ok()
+            .map(|i| start + i)
+    }
+
+    /// Number of kmers in the index
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.kmers().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.kmers().is_empty()
+    }
+
+    /// Whether the index is backed by a memory-mapped file
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.storage, Storage::Mapped { .. })
+    }
+
+    /// Size of the index arrays, in bytes
+    pub fn size_in_bytes(&self) -> usize {
+        std::mem::size_of_val(self.kmers())
+            + std::mem::size_of_val(self.file_indices())
+            + std::mem::size_of_val(self.offsets())
+    }
+
+    /// Number of kmers for each of the `n` files
+    pub fn counts(&self, n: usize) -> Vec<usize> {
+        let mut counts = vec![0; n];
+        for &file_index in self.file_indices() {
+            counts[file_index as usize] += 1;
+        }
+        counts
+    }
+
+    /// CRC32 of the index arrays, as laid out on disk
+    pub fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        self.update_checksum(&mut hasher);
+        hasher.finalize()
+    }
+
+    fn update_checksum(&self, hasher: &mut crc32fast::Hasher) {
+        hasher.update(as_bytes(self.offsets()));
+        hasher.update(as_bytes(self.kmers()));
+        hasher.update(as_bytes(self.file_indices()));
+    }
+
+    /// Split the index back into sorted kmer lists for each of the `n` files
+    pub fn to_kmer_lists(&self, n: usize) -> Vec<Vec<K>> {
+        self.filter_kmer_lists(n, |_| true)
+    }
+
+    /// Split the index back into sorted kmer lists for each of the `n` files,
+    /// keeping the kmers whose position passes `keep`
+    pub fn filter_kmer_lists(&self, n: usize, keep: impl Fn(usize) -> bool) -> Vec<Vec<K>> {
+        let mut kmers = vec![Vec::new(); n];
+        for (i, (&kmer, &file_index)) in self.kmers().iter().zip(self.file_indices()).enumerate() {
+            if keep(i) {
+                kmers[file_index as usize].push(kmer);
+            }
+        }
+        kmers
+    }
+}
+
+/// Kmer indices for the group levels of the class hierarchy
+pub struct GroupIndex<K = u64> {
+    indices: Vec<KmerIndex<K>>,
+    /// Class index => group index, per level
+    members: Vec<Vec<usize>>,
+    /// Group index => group index at the next level, per level
+    parents: Vec<Vec<usize>>,
+}
+
+impl<K: Kmer> GroupIndex<K> {
+    /// Build the indices, moving the group kmers out of `levels`
+    pub fn new(kmer_size: u8, levels: &mut [GroupLevel<K>]) -> GroupIndex<K> {
+        let indices = levels
+            .iter_mut()
+            .map(|level| KmerIndex::new(kmer_size, std::mem::take(&mut level.kmers)))
+            .collect::<Vec<_>>();
+        GroupIndex::from_indices(indices, levels)
+    }
+
+    /// Use the given index of the group kmers at each level
+    fn from_indices(indices: Vec<KmerIndex<K>>, levels: &[GroupLevel<K>]) -> GroupIndex<K> {
+        let members = levels
+            .iter()
+            .map(|level| level.members.clone())
+            .collect::<Vec<_>>();
+        let parents = levels
+            .windows(2)
+            .map(|pair| {
+                let mut parents = vec![0; pair[0].groups.len()];
+                for (&group, &parent) in pair[0].members.iter().zip(pair[1].members.iter()) {
+                    parents[group] = parent;
+                }
+                parents
+            })
+            .collect::<Vec<_>>();
+        GroupIndex {
+            indices,
+            members,
+            parents,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Index of the group kmers at one level
+    pub fn level(&self, level: usize) -> &KmerIndex<K> {
+        &self.indices[level]
+    }
+
+    /// Get the level and group index of a kmer unique to a group
+    #[inline]
+    pub fn get(&self, kmer: K) -> Option<(usize, usize)> {
+        self.indices
+            .iter()
+            .enumerate()
+            .find_map(|(level, index)| index.get(kmer).map(|group| (level, group)))
+    }
+
+    /// Kmer counts of each group at each level, from the counts of singleton
+    /// kmers in each class and of group kmers found at each level
+    pub fn counts(&self, class_counts: &[i32], group_counts: &[Vec<i32>]) -> Vec<Vec<i32>> {
+        let mut counts: Vec<Vec<i32>> = Vec::with_capacity(self.indices.len());
+        for (level, hits) in group_counts.iter().enumerate() {
+            let mut level_counts = hits.clone();
+            if level == 0 {
+                for (&group, &count) in self.members[0].iter().zip(class_counts) {
+                    level_counts[group] += count;
+                }
+            } else {
+                for (group, &count) in counts[level - 1].iter().enumerate() {
+                    level_counts[self.parents[level - 1][group]] += count;
+                }
+            }
+            counts.push(level_counts);
+        }
+        counts
+    }
+}
+
+/// View a region of the memory map as a slice of `T`
+#[inline]
+fn mapped_slice<T>(mmap: &Mmap, start: usize, len: usize) -> &[T] {
+    let bytes = &mmap[start..start + len * std::mem::size_of::<T>()];
+    // SAFETY: the layout is checked when the file is opened; every array starts
+    // at a multiple of its alignment from the page-aligned map, in native byte
+    // order.
+    unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const T, len) }
+}
+
+/// View a slice of integers as bytes, in native byte order
+#[inline]
+fn as_bytes<T>(x: &[T]) -> &[u8] {
+    // SAFETY: only used on integer slices, which have no padding
+    unsafe { std::slice::from_raw_parts(x.as_ptr() as *const u8, std::mem::size_of_val(x)) }
+}
+
+/// Round up to the next multiple of 8 bytes
+#[inline]
+fn align8(x: usize) -> usize {
+    (x + 7) & !7
+}
+
+/// Start of the kmers array, which follows the offsets array aligned for `K`
+#[inline]
+fn kmers_start<K>(offsets_end: usize) -> usize {
+    let align = std::mem::align_of::<K>().max(8);
+    offsets_end.div_ceil(align) * align
+}
+
+/// Check if the file starts with the memory-mapped database magic bytes
+pub fn is_kmer_index(index_file: &str) -> bool {
+    let mut magic = [0u8; 8];
+    File::open(index_file)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map(|_| &magic == INDEX_MAGIC)
+        .unwrap_or(false)
+}
+
+/// Read the kmer size from the metadata of a memory-mapped database
+pub fn index_kmer_size(index_file: &str) -> u8 {
+    let mut reader = File::open(index_file).unwrap();
+    let mut header = [0u8; HEADER_SIZE];
+    reader
+        .read_exact(&mut header)
+        .unwrap_or_else(|_| panic!("`{}` is truncated", index_file));
+    let meta_len = u64::from_le_bytes(header[32..40].try_into().unwrap());
+    let header: DbHeader = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(meta_len)
+        .deserialize_from(reader)
+        .unwrap_or_else(|e| panic!("`{}` has corrupt metadata: {}", index_file, e));
+    header.kmer_size
+}
+
+/// Metadata stored in a memory-mapped database, without the kmers, which are
+/// stored as arrays
+fn mapped_metadata<K: Kmer>(singleton_kmers: &SingletonKmers<K>) -> SingletonKmers<K> {
+    let mut meta = singleton_kmers.metadata();
+    for level in meta.groups.iter_mut() {
+        level.kmers = Vec::new();
+    }
+    meta
+}
+
+/// CRC32 of a memory-mapped database: its header but the checksum itself, its
+/// metadata and the arrays of the index and of each group level
+pub fn mapped_checksum<K: Kmer>(
+    singleton_kmers: &SingletonKmers<K>,
+    index: &KmerIndex<K>,
+    group_index: &GroupIndex<K>,
+) -> u32 {
+    let header = &singleton_kmers.header;
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(
+        &serialize(&(header.kmer_size, header.canonical, &header.params))
+            .expect("serialization to succeed"),
+    );
+    hasher.update(&serialize(&mapped_metadata(singleton_kmers)).expect("serialization to succeed"));
+    index.update_checksum(&mut hasher);
+    for level in group_index.indices.iter() {
+        level.update_checksum(&mut hasher);
+    }
+    hasher.finalize()
+}
+
+/// Write the offsets, kmers and file indices of an index starting at `pos`,
+/// which is aligned to 8 bytes, returning the end position
+fn write_arrays<K: Kmer>(writer: &mut impl Write, pos: usize, index: &KmerIndex<K>) -> usize {
+    let (kmers, file_indices, offsets) = (index.kmers(), index.file_indices(), index.offsets());
+    writer.write_all(as_bytes(offsets)).unwrap();
+    let offsets_end = pos + std::mem::size_of_val(offsets);
+    let kmers_start = kmers_start::<K>(offsets_end);
+    writer
+        .write_all(&vec![0u8; kmers_start - offsets_end])
+        .unwrap();
+    writer.write_all(as_bytes(kmers)).unwrap();
+    writer.write_all(as_bytes(file_indices)).unwrap();
+    kmers_start + std::mem::size_of_val(kmers) + std::mem::size_of_val(file_indices)
+}
+
+/// Write the kmer metadata and index in the memory-mapped layout
+///
+/// Layout (little-endian): fixed header, bincode metadata (`DbHeader` and
+/// `SingletonKmers` without kmers), then the offsets, kmers and file indices
+/// arrays, each aligned to 8 bytes (16 bytes for `u128` kmers). Each group
+/// level follows with a small header and the same arrays. The checksum covers
+/// the metadata and all arrays.
+pub fn write_kmer_index<K: Kmer>(
+    index_file: &str,
+    singleton_kmers: &SingletonKmers<K>,
+    index: &KmerIndex<K>,
+) {
+    if cfg!(target_endian = "big") {
+        panic!("memory-mapped databases require a little-endian platform");
+    }
+    let mut levels = singleton_kmers.groups.clone();
+    let group_index = GroupIndex::new(singleton_kmers.kmer_size, &mut levels);
+    let header = DbHeader {
+        version: INDEX_VERSION,
+        checksum: mapped_checksum(singleton_kmers, index, &group_index),
+        ..singleton_kmers.header.clone()
+    };
+    let meta =
+        serialize(&(header, mapped_metadata(singleton_kmers))).expect("serialization to succeed");
+
+    let mut writer = BufWriter::new(File::create(index_file).unwrap());
+    writer.write_all(INDEX_MAGIC).unwrap();
+    writer.write_all(&INDEX_VERSION.to_le_bytes()).unwrap();
+    writer.write_all(&index.shift.to_le_bytes()).unwrap();
+    writer
+        .write_all(&(index.len() as u64).to_le_bytes())
+        .unwrap();
+    writer
+        .write_all(&(index.offsets().len() as u64).to_le_bytes())
+        .unwrap();
+    writer
+        .write_all(&(meta.len() as u64).to_le_bytes())
+        .unwrap();
+    writer.write_all(&[0u8; 8]).unwrap();
+    writer.write_all(&meta).unwrap();
+    writer
+        .write_all(&vec![0u8; align8(meta.len()) - meta.len()])
+        .unwrap();
+    let mut pos = write_arrays(&mut writer, HEADER_SIZE + align8(meta.len()), index);
+    for level in group_index.indices.iter() {
+        writer.write_all(&vec![0u8; align8(pos) - pos]).unwrap();
+        writer.write_all(&level.shift.to_le_bytes()).unwrap();
+        writer.write_all(&[0u8; 4]).unwrap();
+        writer
+            .write_all(&(level.len() as u64).to_le_bytes())
+            .unwrap();
+        writer
+            .write_all(&(level.offsets().len() as u64).to_le_bytes())
+            .unwrap();
+        pos = write_arrays(&mut writer, align8(pos) + LEVEL_HEADER_SIZE, level);
+    }
+    writer.flush().unwrap();
+}
+
+/// Map the arrays of an index starting at `offsets_start`, checking them against
+/// the file length. Returns the index and the end of its arrays.
+fn map_arrays<K: Kmer>(
+    mmap: &Arc<Mmap>,
+    index_file: &str,
+    shift: u32,
+    n_kmers: usize,
+    n_offsets: usize,
+    offsets_start: usize,
+) -> (KmerIndex<K>, usize) {
+    // Array bounds from the header counts, which may be corrupt
+    let layout = n_offsets
+        .checked_mul(std::mem::size_of::<u64>())
+        .and_then(|x| x.checked_add(offsets_start))
+        .filter(|&x| x <= mmap.len())
+        .map(kmers_start::<K>)
+        .and_then(|kmers_start| {
+            let file_indices_start = n_kmers
+                .checked_mul(std::mem::size_of::<K>())?
+                .checked_add(kmers_start)?;
+            let end = n_kmers
+                .checked_mul(std::mem::size_of::<u16>())?
+                .checked_add(file_indices_start)?;
+            Some((kmers_start, file_indices_start, end))
+        });
+    let Some((kmers_start, file_indices_start, end)) = layout.filter(|x| x.2 <= mmap.len()) else {
+        panic!(
+            "`{}` is truncated or corrupt ({} kmers, {} offsets in {} bytes)",
+            index_file,
+            n_kmers,
+            n_offsets,
+            mmap.len()
+        );
+    };
+    // Lookups slice the kmers between consecutive offsets
+    let offsets: &[u64] = mapped_slice(mmap, offsets_start, n_offsets);
+    assert!(
+        (shift as usize) < 8 * std::mem::size_of::<K>()
+            && offsets.first() == Some(&0)
+            && offsets.last() == Some(&(n_kmers as u64)),
+        "`{}` has a corrupt offsets directory",
+        index_file
+    );
+    let index = KmerIndex {
+        storage: Storage::Mapped {
+            mmap: mmap.clone(),
+            n_kmers,
+            n_offsets,
+            offsets_start,
+            kmers_start,
+            file_indices_start,
+        },
+        shift,
+    };
+    (index, end)
+}
+
+/// Open a memory-mapped kmer database, returning the metadata, the index and
+/// the index of the group kmers
+///
+/// Only the layout is checked here, so that opening stays cheap; see
+/// `load_kmer_index` to also verify the checksum of the contents.
+pub fn open_kmer_index<K: Kmer>(
+    index_file: &str,
+) -> (SingletonKmers<K>, KmerIndex<K>, GroupIndex<K>) {
+    if cfg!(target_endian = "big") {
+        panic!("memory-mapped databases require a little-endian platform");
+    }
+    let file = File::open(index_file).unwrap();
+    // SAFETY: the database is not expected to be modified while in use
+    let mmap = Arc::new(unsafe { Mmap::map(&file) }.expect("valid memory map"));
+    assert!(
+        mmap.len() >= HEADER_SIZE && &mmap[..8] == INDEX_MAGIC,
+        "`{}` is not a memory-mapped kmer database",
+        index_file
+    );
+    let u32_at = |i: usize| u32::from_le_bytes(mmap[i..i + 4].try_into().unwrap());
+    let u64_at = |i: usize| u64::from_le_bytes(mmap[i..i + 8].try_into().unwrap()) as usize;
+    let version = u32_at(8);
+    assert!(
+        version == INDEX_VERSION,
+        "`{}` has unsupported format version {} (expected {}), please rebuild it",
+        index_file,
+        version,
+        INDEX_VERSION
+    );
+    let shift = u32_at(12);
+    let n_kmers = u64_at(16);
+    let n_offsets = u64_at(24);
+    let meta_len = u64_at(32);
+    let meta_end = HEADER_SIZE
+        .checked_add(meta_len)
+        .filter(|&end| end <= mmap.len())
+        .unwrap_or_else(|| panic!("`{}` is truncated or corrupt", index_file));
+    let kmer_size = deserialize::<DbHeader>(&mmap[HEADER_SIZE..meta_end])
+        .unwrap_or_else(|e| panic!("`{}` has corrupt metadata: {}", index_file, e))
+        .kmer_size;
+    assert_kmer_width::<K>(kmer_size, index_file);
+    let (mut header, mut singleton_kmers): (DbHeader, SingletonKmers<K>) =
+        deserialize(&mmap[HEADER_SIZE..meta_end])
+            .unwrap_or_else(|e| panic!("`{}` has corrupt metadata: {}", index_file, e));
+    assert!(
+        header.kmer_size == singleton_kmers.kmer_size,
+        "`{}` has inconsistent kmer sizes ({} in header, {} in metadata)",
+        index_file,
+        header.kmer_size,
+        singleton_kmers.kmer_size
+    );
+    header.version = version;
+    singleton_kmers.header = header;
+
+    let offsets_start = HEADER_SIZE + align8(meta_len);
+    let (index, mut pos) = map_arrays(&mmap, index_file, shift, n_kmers, n_offsets, offsets_start);
+    let mut levels = Vec::with_capacity(singleton_kmers.groups.len());
+    for _ in singleton_kmers.groups.iter() {
+        let start = align8(pos);
+        assert!(
+            start + LEVEL_HEADER_SIZE <= mmap.len(),
+            "`{}` is truncated or corrupt",
+            index_file
+        );
+        let (level, end) = map_arrays(
+            &mmap,
+            index_file,
+            u32_at(start),
+            u64_at(start + 8),
+            u64_at(start + 16),
+            start + LEVEL_HEADER_SIZE,
+        );
+        levels.push(level);
+        pos = end;
+    }
+    assert!(
+        mmap.len() == pos,
+        "`{}` is truncated or corrupt ({} bytes, expected {})",
+        index_file,
+        mmap.len(),
+        pos
+    );
+    let group_index = GroupIndex::from_indices(levels, &singleton_kmers.groups);
+    (singleton_kmers, index, group_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::sample_db;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_kmer_index() {
+        let mut kmers = sample_db::<u64>(15).kmers;
+        kmers.insert(1, Vec::new());
+        let index = KmerIndex::new(15, kmers.clone());
+        let mut sorted = kmers.concat();
+        sorted.sort_unstable();
+        assert_eq!(index.len(), sorted.len());
+        for (file_index, file_kmers) in kmers.iter().enumerate() {
+            for &kmer in file_kmers.iter() {
+                assert_eq!(index.get(kmer), Some(file_index));
+                assert_eq!(index.position(kmer), sorted.binary_search(&kmer).ok());
+            }
+        }
+        // Kmers of another class, and past the last offset
+        for kmer in sample_db::<u64>(15).groups[0].kmers[0].iter() {
+            assert_eq!(index.get(*kmer), None);
+        }
+        assert_eq!(index.get(u64::MAX), None);
+        assert_eq!(index.to_kmer_lists(kmers.len()), kmers);
+        assert_eq!(
+            index.counts(kmers.len()),
+            kmers.iter().map(|x| x.len()).collect::<Vec<_>>()
+        );
+    }
+
+    /// Write a sample database memory-mapped and check it opens the same
+    fn check_mapped<K: Kmer>(kmer_size: u8) {
+        let singleton_kmers = sample_db::<K>(kmer_size);
+        let index = KmerIndex::new(kmer_size, singleton_kmers.kmers.clone());
+        let index_file = NamedTempFile::new().unwrap();
+        let index_file = index_file.path().to_str().unwrap();
+        write_kmer_index(index_file, &singleton_kmers, &index);
+        assert!(is_kmer_index(index_file));
+        assert_eq!(index_kmer_size(index_file), kmer_size);
+
+        let (mapped_kmers, mapped, mapped_groups) = open_kmer_index::<K>(index_file);
+        let header = &mapped_kmers.header;
+        assert!(mapped.is_mapped());
+        assert_eq!(header.version, INDEX_VERSION);
+        assert_eq!(header.params, singleton_kmers.header.params);
+        assert_eq!(
+            mapped_checksum(&mapped_kmers, &mapped, &mapped_groups),
+            header.checksum
+        );
+        assert_eq!(mapped_kmers.fasta_files, singleton_kmers.fasta_files);
+        assert_eq!(mapped.to_kmer_lists(3), singleton_kmers.kmers);
+        assert_eq!(mapped_kmers.groups.len(), singleton_kmers.groups.len());
+        for (l, level) in singleton_kmers.groups.iter().enumerate() {
+            let mapped_level = &mapped_kmers.groups[l];
+            assert_eq!(mapped_level.groups, level.groups);
+            assert_eq!(mapped_level.members, level.members);
+            assert!(mapped_level.kmers.is_empty());
+            assert_eq!(
+                mapped_groups.level(l).to_kmer_lists(level.groups.len()),
+                level.kmers
+            );
+            for (group, kmers) in level.kmers.iter().enumerate() {
+                assert!(kmers
+                    .iter()
+                    .all(|&x| mapped_groups.get(x) == Some((l, group))));
+            }
+        }
+    }
+
+    #[test]
+    fn test_mapped_index() {
+        check_mapped::<u64>(21);
+        check_mapped::<u128>(41);
+    }
+}
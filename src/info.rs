@@ -1,56 +1,339 @@
-use crate::models::SingletonKmers;
+use crate::db::{kmer_size_of, load_kmer_index};
+use crate::index::{mapped_checksum, GroupIndex, KmerIndex};
+use crate::kmer::{is_wide, Kmer};
+use crate::models::{open_fastx, prefix, SingletonKmers};
+use crate::sampling::for_each_sampled_kmer;
 
-use bincode::deserialize_from;
-use clap::Parser;
-use std::collections::HashMap;
+use clap::{Parser, ValueEnum};
+use needletail::Sequence;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufWriter, Write};
 
 #[derive(Parser, Debug)]
 pub struct InfoArgs {
     /// Bincode file
     pub bincode_file: String,
+    /// Reference FASTA files to report the unique kmer density along
+    #[clap(long, num_args = 1..)]
+    pub reference: Vec<String>,
+    /// Window size for the unique kmer density
+    #[clap(long, default_value_t = 100_000)]
+    pub window: usize,
+    /// Minimum length of the regions without unique kmers to report
+    #[clap(long, default_value_t = 10_000)]
+    pub min_desert: usize,
+    /// Output format
+    #[clap(long, value_enum, default_value_t)]
+    pub format: InfoFormat,
 }
 
-/// Load the bincode file and print some information about it.
-pub fn load_kmer_db(bincode_file: &str) -> SingletonKmers {
-    let reader = BufReader::new(File::open(bincode_file).unwrap());
-    let singleton_kmers: SingletonKmers = deserialize_from(reader).unwrap();
-    log::info!(
-        "Loaded singleton kmers (K={}) from `{}`",
-        singleton_kmers.kmer_size,
-        bincode_file
-    );
-    singleton_kmers
-}
-
-/// Create mapping between kmer and the file index
-pub fn map_kmer_to_file(singleton_kmers: &SingletonKmers) -> HashMap<u64, usize> {
-    // Convert to kmer => file index
-    let mut kmer_to_file = HashMap::new();
-    for (file_index, kmer_set) in singleton_kmers.kmers.iter().enumerate() {
-        for &kmer in kmer_set.iter() {
-            kmer_to_file.insert(kmer, file_index);
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InfoFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object
+    Json,
+    /// Field, name and value columns
+    Tsv,
+}
+
+/// Summary of a database, as printed by `info`
+#[derive(Serialize)]
+struct InfoReport {
+    file: String,
+    file_size: u64,
+    format: String,
+    version: u32,
+    checksum: String,
+    checksum_ok: bool,
+    kmer_size: u8,
+    canonical: bool,
+    sampling: String,
+    params: BTreeMap<String, String>,
+    kmers: usize,
+    classes: Vec<NamedCount>,
+    groups: Vec<GroupReport>,
+    index_bytes: usize,
+}
+
+#[derive(Serialize)]
+struct NamedCount {
+    name: String,
+    kmers: usize,
+}
+
+#[derive(Serialize)]
+struct GroupReport {
+    level: String,
+    groups: Vec<NamedCount>,
+}
+
+/// Estimate the memory of a `HashMap<K, usize>` holding `n` kmers
+fn hashmap_size_in_bytes<K>(n: usize) -> usize {
+    // Buckets are kept at most 7/8 full, with one control byte per bucket
+    let buckets = (n * 8 / 7).max(1).next_power_of_two();
+    buckets * (std::mem::size_of::<(K, usize)>() + 1)
+}
+
+pub fn info(args: &InfoArgs) {
+    if is_wide(kmer_size_of(&args.bincode_file)) {
+        info_with::<u128>(args);
+    } else {
+        info_with::<u64>(args);
+    }
+}
+
+fn info_with<K: Kmer>(args: &InfoArgs) {
+    let bincode_file = &args.bincode_file;
+    let (singleton_kmers, kmer_to_file, kmer_to_group) = load_kmer_index::<K>(bincode_file, false);
+    let header = &singleton_kmers.header;
+    let counts = kmer_to_file.counts(singleton_kmers.n());
+    // Bincode checksums are verified when loading, mapped ones are reported
+    let checksum_ok = !kmer_to_file.is_mapped()
+        || mapped_checksum(&singleton_kmers, &kmer_to_file, &kmer_to_group) == header.checksum;
+    match args.format {
+        InfoFormat::Text => {
+            println!(
+                "Format: {}",
+                match (kmer_to_file.is_mapped(), header.version) {
+                    (true, version) => format!("memory-mapped (version {})", version),
+                    (false, 0) => "bincode (no header)".to_string(),
+                    (false, version) => format!("bincode (version {})", version),
+                }
+            );
+            if header.version > 0 {
+                println!(
+                    "Checksum: {:08x} ({})",
+                    header.checksum,
+                    if checksum_ok { "OK" } else { "MISMATCH" }
+                );
+            }
+            println!("Kmer size: {}", singleton_kmers.kmer_size);
+            println!("Canonical kmers: {}", header.canonical);
+            println!("Sampling: {}", singleton_kmers.sampling);
+            for (key, value) in header.params.iter() {
+                println!("Build parameter {}: {}", key, value);
+            }
+            println!("Number of fasta files: {}", singleton_kmers.n());
+            println!("Number of kmers: {}", kmer_to_file.len());
+            for (i, fasta_file) in singleton_kmers.fasta_files.iter().enumerate() {
+                println!("  {}: {} ({} mers)", i + 1, fasta_file, counts[i]);
+            }
+            for (l, level) in singleton_kmers.groups.iter().enumerate() {
+                println!("Group level {}: {} groups", level.name, level.groups.len());
+                let group_counts = kmer_to_group.level(l).counts(level.groups.len());
+                for (i, group) in level.groups.iter().enumerate() {
+                    println!("  {}: {} ({} mers)", i + 1, group, group_counts[i]);
+                }
+            }
+            println!(
+                "Index memory: {} MiB{} (vs. {} MiB as HashMap)",
+                kmer_to_file.size_in_bytes() >> 20,
+                if kmer_to_file.is_mapped() {
+                    ", memory-mapped"
+                } else {
+                    ""
+                },
+                hashmap_size_in_bytes::<K>(kmer_to_file.len()) >> 20
+            );
         }
+        InfoFormat::Json => {
+            let report = info_report(
+                bincode_file,
+                &singleton_kmers,
+                &kmer_to_file,
+                &kmer_to_group,
+                checksum_ok,
+            );
+            serde_json::to_writer_pretty(std::io::stdout(), &report)
+                .expect("serialization to succeed");
+            println!();
+        }
+        InfoFormat::Tsv => {
+            let report = info_report(
+                bincode_file,
+                &singleton_kmers,
+                &kmer_to_file,
+                &kmer_to_group,
+                checksum_ok,
+            );
+            println!("Field\tName\tValue");
+            println!("file\t\t{}", report.file);
+            println!("file_size\t\t{}", report.file_size);
+            println!("format\t\t{}", report.format);
+            println!("version\t\t{}", report.version);
+            println!("checksum\t\t{}", report.checksum);
+            println!("checksum_ok\t\t{}", report.checksum_ok);
+            println!("kmer_size\t\t{}", report.kmer_size);
+            println!("canonical\t\t{}", report.canonical);
+            println!("sampling\t\t{}", report.sampling);
+            for (key, value) in header.params.iter() {
+                println!("param\t{}\t{}", key, value);
+            }
+            println!("kmers\t\t{}", report.kmers);
+            for class in report.classes.iter() {
+                println!("class\t{}\t{}", class.name, class.kmers);
+            }
+            for level in report.groups.iter() {
+                for group in level.groups.iter() {
+                    println!("group\t{}:{}\t{}", level.level, group.name, group.kmers);
+                }
+            }
+            println!("index_bytes\t\t{}", report.index_bytes);
+        }
+    }
+    if !args.reference.is_empty() {
+        assert!(args.window > 0, "window size must be at least 1");
+        args.reference.par_iter().for_each(|fasta_file| {
+            density_one(&singleton_kmers, &kmer_to_file, fasta_file, args);
+        });
+    }
+}
+
+/// Collect the summary of a database for structured output
+fn info_report<K: Kmer>(
+    bincode_file: &str,
+    singleton_kmers: &SingletonKmers<K>,
+    kmer_to_file: &KmerIndex<K>,
+    kmer_to_group: &GroupIndex<K>,
+    checksum_ok: bool,
+) -> InfoReport {
+    let header = &singleton_kmers.header;
+    let counts = kmer_to_file.counts(singleton_kmers.n());
+    InfoReport {
+        file: bincode_file.to_string(),
+        file_size: std::fs::metadata(bincode_file).unwrap().len(),
+        format: match (kmer_to_file.is_mapped(), header.version) {
+            (true, _) => "memory-mapped",
+            (false, 0) => "bincode (no header)",
+            (false, _) => "bincode",
+        }
+        .to_string(),
+        version: header.version,
+        checksum: format!("{:08x}", header.checksum),
+        checksum_ok,
+        kmer_size: singleton_kmers.kmer_size,
+        canonical: header.canonical,
+        sampling: singleton_kmers.sampling.to_string(),
+        params: header.params.iter().cloned().collect(),
+        kmers: kmer_to_file.len(),
+        classes: singleton_kmers
+            .fasta_files
+            .iter()
+            .zip(counts)
+            .map(|(name, kmers)| NamedCount {
+                name: name.clone(),
+                kmers,
+            })
+            .collect(),
+        groups: singleton_kmers
+            .groups
+            .iter()
+            .enumerate()
+            .map(|(l, level)| GroupReport {
+                level: level.name.clone(),
+                groups: level
+                    .groups
+                    .iter()
+                    .zip(kmer_to_group.level(l).counts(level.groups.len()))
+                    .map(|(name, kmers)| NamedCount {
+                        name: name.clone(),
+                        kmers,
+                    })
+                    .collect(),
+            })
+            .collect(),
+        index_bytes: kmer_to_file.size_in_bytes(),
     }
-    log::info!("Mapped kmers to files");
-    kmer_to_file
-}
-
-pub fn info(bincode_file: &str) {
-    let singleton_kmers = load_kmer_db(bincode_file);
-    println!("Kmer size: {}", singleton_kmers.kmer_size);
-    println!("Number of fasta files: {}", singleton_kmers.n());
-    println!(
-        "Number of kmers: {}",
-        singleton_kmers.kmers.iter().map(|x| x.len()).sum::<usize>()
-    );
-    for (i, fasta_file) in singleton_kmers.fasta_files.iter().enumerate() {
-        println!(
-            "  {}: {} ({} mers)",
-            i + 1,
-            fasta_file,
-            singleton_kmers.kmers[i].len()
+}
+
+/// Write the number of unique kmers per window along each sequence of a
+/// reference as bedGraph, and the regions without any unique kmer as BED.
+/// Breakpoints within these deserts cannot be detected.
+fn density_one<K: Kmer>(
+    singleton_kmers: &SingletonKmers<K>,
+    kmer_to_file: &KmerIndex<K>,
+    fasta_file: &str,
+    args: &InfoArgs,
+) {
+    let mut reader = open_fastx(fasta_file).expect("valid FASTA file");
+    let file_prefix = prefix(fasta_file);
+    let density_file = file_prefix.to_string() + ".density.bedgraph";
+    let deserts_file = file_prefix.to_string() + ".deserts.bed";
+    let mut density_writer = BufWriter::new(File::create(&density_file).unwrap());
+    let mut deserts_writer = BufWriter::new(File::create(&deserts_file).unwrap());
+    writeln!(
+        density_writer,
+        "track type=bedGraph name=\"{} unique kmers per {} bp\"",
+        file_prefix, args.window
+    )
+    .unwrap();
+
+    let kmer_size = singleton_kmers.kmer_size;
+    let sampling = singleton_kmers.sampling;
+    while let Some(record) = reader.next() {
+        let record = record.expect("valid record");
+        let seq = record.normalize(false);
+        let id = String::from_utf8(record.id().to_vec())
+            .unwrap()
+            .split_whitespace()
+            .next()
+            .unwrap()
+            .to_string();
+        let mut windows = vec![0; seq.len().div_ceil(args.window)];
+        let mut class_counts = vec![0; singleton_kmers.n()];
+        let mut deserts = Vec::new();
+        // End of the last unique kmer, where the current desert starts
+        let mut last_end = 0;
+        for_each_sampled_kmer(&seq, kmer_size, sampling, |pos, kmer: K| {
+            if let Some(file_index) = kmer_to_file.get(kmer) {
+                windows[pos / args.window] += 1;
+                class_counts[file_index] += 1;
+                if pos >= last_end + args.min_desert {
+                    deserts.push((last_end, pos));
+                }
+                last_end = pos + kmer_size as usize;
+            }
+        });
+        if seq.len() >= last_end + args.min_desert {
+            deserts.push((last_end, seq.len()));
+        }
+        for (i, count) in windows.iter().enumerate() {
+            let start = i * args.window;
+            let end = (start + args.window).min(seq.len());
+            writeln!(density_writer, "{}\t{}\t{}\t{}", id, start, end, count).unwrap();
+        }
+        for (start, end) in deserts.iter() {
+            writeln!(
+                deserts_writer,
+                "{}\t{}\t{}\t{}",
+                id,
+                start,
+                end,
+                end - start
+            )
+            .unwrap();
+        }
+        let desert_length: usize = deserts.iter().map(|(start, end)| end - start).sum();
+        let dominant = class_counts
+            .iter()
+            .enumerate()
+            .max_by_key(|&(_, count)| *count)
+            .filter(|&(_, count)| *count > 0)
+            .map_or("-", |(i, _)| singleton_kmers.fasta_files[i].as_str());
+        log::info!(
+            "{}: {} unique kmers (mostly {}), {} deserts spanning {} bp",
+            id,
+            class_counts.iter().sum::<usize>(),
+            dominant,
+            deserts.len(),
+            desert_length
         );
     }
+    log::info!("Unique kmer density written to `{}`", density_file);
+    log::info!("Unique kmer deserts written to `{}`", deserts_file);
 }
@@ -0,0 +1,204 @@
+use needletail::Sequence;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::hash::Hash;
+use std::io::Write;
+
+/// 2-bit encoded kmer, with the first base in the highest bits. `u64` holds
+/// kmers up to 32 bases, and `u128` up to 64 bases.
+pub trait Kmer:
+    Copy
+    + Ord
+    + Hash
+    + Default
+    + fmt::Debug
+    + fmt::Display
+    + Send
+    + Sync
+    + Serialize
+    + DeserializeOwned
+    + 'static
+{
+    /// Largest kmer size that fits
+    const MAX_KMER_SIZE: u8;
+    /// Size of the little-endian encoding
+    const BYTES: usize;
+
+    /// Call `f` on the position and canonical value of every kmer in a
+    /// normalized sequence, skipping kmers with ambiguous bases
+    fn for_each_canonical(seq: &[u8], kmer_size: u8, f: impl FnMut(usize, Self));
+
+    /// Bits of the kmer above `shift`, which must fit in 64 bits
+    fn high_bits(self, shift: u32) -> u64;
+
+    /// 64-bit hash of the kmer, used to sample kmers
+    fn hash64(self) -> u64;
+
+    fn write_le(self, writer: &mut impl Write) -> std::io::Result<()>;
+
+    fn from_le_slice(bytes: &[u8]) -> Self;
+}
+
+/// Whether kmers of this size need the `u128` representation
+#[inline]
+pub fn is_wide(kmer_size: u8) -> bool {
+    kmer_size > u64::MAX_KMER_SIZE
+}
+
+/// Check that a database of this kmer size is read with the matching `K`
+pub fn assert_kmer_width<K: Kmer>(kmer_size: u8, file: &str) {
+    assert!(
+        is_wide(kmer_size) == is_wide(K::MAX_KMER_SIZE),
+        "`{}` has {}-mers, which are not stored in {} bytes",
+        file,
+        kmer_size,
+        K::BYTES
+    );
+}
+
+/// Check that the kmer size is supported
+pub fn validate_kmer_size(kmer_size: u8) {
+    assert!(
+        kmer_size > 0 && kmer_size <= u128::MAX_KMER_SIZE,
+        "kmer size must be between 1 and {}",
+        u128::MAX_KMER_SIZE
+    );
+}
+
+/// Invertible 64-bit mix (MurmurHash3 finalizer)
+#[inline]
+pub fn mix64(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+    x ^= x >> 33;
+    x
+}
+
+impl Kmer for u64 {
+    const MAX_KMER_SIZE: u8 = 32;
+    const BYTES: usize = 8;
+
+    #[inline]
+    fn for_each_canonical(seq: &[u8], kmer_size: u8, mut f: impl FnMut(usize, Self)) {
+        for (pos, kmer, _) in seq.bit_kmers(kmer_size, true) {
+            f(pos, kmer.0);
+        }
+    }
+
+    #[inline]
+    fn high_bits(self, shift: u32) -> u64 {
+        self.checked_shr(shift).unwrap_or(0)
+    }
+
+    #[inline]
+    fn hash64(self) -> u64 {
+        mix64(self)
+    }
+
+    fn write_le(self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+
+    fn from_le_slice(bytes: &[u8]) -> Self {
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl Kmer for u128 {
+    const MAX_KMER_SIZE: u8 = 64;
+    const BYTES: usize = 16;
+
+    /// Rolling encoder of both strands, as `bit_kmers` only handles `u64`
+    fn for_each_canonical(seq: &[u8], kmer_size: u8, mut f: impl FnMut(usize, Self)) {
+        let k = kmer_size as usize;
+        let mask = u128::MAX >> (128 - 2 * k);
+        let rc_shift = 2 * (k - 1);
+        let mut forward = 0u128;
+        let mut reverse = 0u128;
+        let mut len = 0;
+        for (i, &base) in seq.iter().enumerate() {
+            let bits = match base {
+                b'A' | b'a' => 0,
+                b'C' | b'c' => 1,
+                b'G' | b'g' => 2,
+                b'T' | b't' => 3,
+                _ => {
+                    len = 0;
+                    continue;
+                }
+            };
+            forward = ((forward << 2) | bits) & mask;
+            reverse = (reverse >> 2) | ((3 - bits) << rc_shift);
+            len += 1;
+            if len >= k {
+                f(i + 1 - k, forward.min(reverse));
+            }
+        }
+    }
+
+    #[inline]
+    fn high_bits(self, shift: u32) -> u64 {
+        self.checked_shr(shift).unwrap_or(0) as u64
+    }
+
+    #[inline]
+    fn hash64(self) -> u64 {
+        mix64(self as u64 ^ mix64((self >> 64) as u64))
+    }
+
+    fn write_le(self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+
+    fn from_le_slice(bytes: &[u8]) -> Self {
+        u128::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::random_seq;
+
+    /// Canonical kmer of a window, encoded base by base
+    fn naive_canonical(window: &[u8]) -> u128 {
+        let encode = |bases: &mut dyn Iterator<Item = u128>| bases.fold(0, |x, b| (x << 2) | b);
+        let bits = |base: &u8| b"ACGT".iter().position(|x| x == base).unwrap() as u128;
+        let forward = encode(&mut window.iter().map(bits));
+        let reverse = encode(&mut window.iter().rev().map(|x| 3 - bits(x)));
+        forward.min(reverse)
+    }
+
+    /// Positions and canonical values of the kmers of a sequence
+    fn canonical<K: Kmer>(seq: &[u8], kmer_size: u8) -> Vec<(usize, K)> {
+        let mut kmers = Vec::new();
+        K::for_each_canonical(seq, kmer_size, |pos, kmer| kmers.push((pos, kmer)));
+        kmers
+    }
+
+    #[test]
+    fn test_u128_canonical() {
+        let mut seq = random_seq(1, 300).into_bytes();
+        seq[200] = b'N';
+        for kmer_size in [33, 64] {
+            let k = kmer_size as usize;
+            let expected = (0..=seq.len() - k)
+                .filter(|&pos| !seq[pos..pos + k].contains(&b'N'))
+                .map(|pos| (pos, naive_canonical(&seq[pos..pos + k])))
+                .collect::<Vec<_>>();
+            assert_eq!(expected.len(), seq.len() - k + 1 - k);
+            assert_eq!(canonical::<u128>(&seq, kmer_size), expected);
+        }
+        // Same encoding as u64 kmers
+        let narrow = canonical::<u64>(&seq, 31);
+        let wide = canonical::<u128>(&seq, 31);
+        assert_eq!(wide.len(), narrow.len());
+        assert!(narrow
+            .iter()
+            .zip(wide)
+            .all(|(x, y)| x.0 == y.0 && x.1 as u128 == y.1));
+    }
+}
@@ -1,9 +1,17 @@
 pub mod breakpoint;
 pub mod build;
 pub mod classify;
+pub mod db;
 pub mod extract;
 pub mod extract_bam;
+pub mod hmm;
+pub mod index;
 pub mod info;
+pub mod kmer;
+pub mod merge;
 pub mod models;
+pub mod reads;
 pub mod regions;
+pub mod sampling;
 pub mod sort_bam;
+pub mod split;
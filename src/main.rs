@@ -7,8 +7,10 @@ use klassify::classify;
 use klassify::extract;
 use klassify::extract_bam;
 use klassify::info;
+use klassify::merge;
 use klassify::regions;
 use klassify::sort_bam;
+use klassify::split;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about=None)]
@@ -25,8 +27,14 @@ enum SubCommand {
     Build(build::BuildArgs),
     #[clap(about = "Classify reads")]
     Classify(classify::ClassifyArgs),
+    #[clap(about = "Filter read classifications again with new thresholds")]
+    Filter(classify::FilterArgs),
     #[clap(about = "Print details about the kmer table")]
     Info(info::InfoArgs),
+    #[clap(about = "Keep only some classes of the kmer table")]
+    Subset(merge::SubsetArgs),
+    #[clap(about = "Merge kmer tables built with the same kmer size")]
+    Merge(merge::MergeArgs),
     #[clap(about = "Extract reads")]
     Extract(extract::ExtractArgs),
     #[clap(about = "Extract reads from BAM")]
@@ -35,6 +43,8 @@ enum SubCommand {
     Regions(regions::RegionsArgs),
     #[clap(about = "Sort BAM file by divergence")]
     SortBam(sort_bam::SortBamArgs),
+    #[clap(about = "Split chimeric reads at the breakpoint")]
+    Split(split::SplitArgs),
 }
 
 fn main() {
@@ -51,21 +61,25 @@ fn main() {
     let args = Args::parse();
     match args.subcommand {
         SubCommand::Build(build) => {
-            build::build(&build.fasta_files, &build.output_file, build.kmer_size);
+            build::build(&build);
         }
         SubCommand::Breakpoint(breakpoint) => {
             breakpoint::breakpoint(&breakpoint.bincode_file, &breakpoint.fasta_files);
         }
         SubCommand::Classify(classify) => {
-            classify::classify(
-                &classify.bincode_file,
-                &classify.reads_file,
-                &classify.output_dir,
-                classify.prefix_length,
-            );
+            classify::classify(&classify);
+        }
+        SubCommand::Filter(filter) => {
+            classify::filter(&filter);
         }
         SubCommand::Info(info) => {
-            info::info(&info.bincode_file);
+            info::info(&info);
+        }
+        SubCommand::Subset(subset) => {
+            merge::subset(&subset);
+        }
+        SubCommand::Merge(merge) => {
+            merge::merge(&merge);
         }
         SubCommand::Extract(extract) => {
             extract::extract(
@@ -92,5 +106,8 @@ fn main() {
                 sort_bam.min_mapq,
             );
         }
+        SubCommand::Split(split) => {
+            split::split(&split);
+        }
     }
 }
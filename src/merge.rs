@@ -0,0 +1,74 @@
+use crate::build::rebuild;
+
+use clap::Parser;
+use regex::Regex;
+
+#[derive(Parser, Debug)]
+pub struct SubsetArgs {
+    /// Bincode file
+    pub bincode_file: String,
+    /// Classes to keep, by name or regular expression matching the whole name
+    #[clap(required = true)]
+    pub classes: Vec<String>,
+    /// Output file
+    #[clap(short, long)]
+    pub output_file: String,
+    /// Write a memory-mapped database that is queried without deserialization
+    #[clap(long, default_value_t = false)]
+    pub mmap: bool,
+    /// Also write the kmers shared between the kept classes
+    #[clap(long, default_value_t = false)]
+    pub keep_shared: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct MergeArgs {
+    /// Bincode files, each with its shared kmers (built with --keep-shared)
+    #[clap(required = true)]
+    pub bincode_files: Vec<String>,
+    /// Output file
+    #[clap(short, long)]
+    pub output_file: String,
+    /// Write a memory-mapped database that is queried without deserialization
+    #[clap(long, default_value_t = false)]
+    pub mmap: bool,
+    /// Also write the kmers shared between the classes
+    #[clap(long, default_value_t = false)]
+    pub keep_shared: bool,
+}
+
+/// Keep only some classes of a database. Kmers shared only between the kept
+/// classes and the others become unique when the shared kmers are available.
+pub fn subset(args: &SubsetArgs) {
+    let patterns = args
+        .classes
+        .iter()
+        .map(|x| Regex::new(&format!("^(?:{})$", x)).expect("valid class regex"))
+        .collect::<Vec<_>>();
+    rebuild(
+        std::slice::from_ref(&args.bincode_file),
+        |name| patterns.iter().any(|x| x.is_match(name)),
+        &args.output_file,
+        args.mmap,
+        args.keep_shared,
+        false,
+        vec![
+            ("subset_of".to_string(), args.bincode_file.clone()),
+            ("classes".to_string(), args.classes.join(",")),
+        ],
+    );
+}
+
+/// Merge databases built with the same kmer size, checking uniqueness across
+/// all of them. Classes with the same name are merged into one.
+pub fn merge(args: &MergeArgs) {
+    rebuild(
+        &args.bincode_files,
+        |_| true,
+        &args.output_file,
+        args.mmap,
+        args.keep_shared,
+        true,
+        vec![("merged_from".to_string(), args.bincode_files.join(","))],
+    );
+}
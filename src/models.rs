@@ -1,6 +1,10 @@
+use crate::sampling::Sampling;
 use log;
+use needletail::errors::ParseError;
+use needletail::{parse_fastx_reader, FastxReader};
 use serde::{Deserialize, Serialize};
 use std::fs;
+use std::io::{Cursor, Read};
 use std::path::Path;
 
 /// Discrete bin size to contract regions
@@ -12,18 +16,162 @@ pub const DEFAULT_FLANK_SIZE: i32 = BINSIZE as i32;
 /// Maximum divergence
 pub const MAX_DE: f32 = 0.01; // 1%
 
-#[derive(Serialize, Deserialize)]
-pub struct SingletonKmers {
+/// File name standing for the standard input
+pub const STDIN: &str = "-";
+/// Magic bytes of zstd frames, which needletail does not detect
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Header stored ahead of the kmers, describing how the database was built
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DbHeader {
+    /// Format version, 0 for databases written without a header
+    #[serde(skip)]
+    pub version: u32,
+    pub kmer_size: u8,
+    /// Whether kmers are stored as min(kmer, reverse complement)
+    pub canonical: bool,
+    /// Build parameters as key-value pairs
+    pub params: Vec<(String, String)>,
+    /// CRC32 of the serialized kmers
+    pub checksum: u32,
+}
+
+/// One level of the class hierarchy, e.g. homolog groups of chromosomes
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct GroupLevel<K = u64> {
+    pub name: String,
+    /// Group names at this level
+    pub groups: Vec<String>,
+    /// Class index => group index
+    pub members: Vec<usize>,
+    /// Kmers shared only by classes of one group, and not already unique to a
+    /// group at a finer level
+    pub kmers: Vec<Vec<K>>,
+}
+
+/// Singleton kmers of each class, as `u64` for kmers up to 32 bases or `u128`
+/// for longer kmers
+#[derive(Serialize, Deserialize, Default)]
+pub struct SingletonKmers<K = u64> {
     pub kmer_size: u8,
     pub fasta_files: Vec<String>,
-    pub kmers: Vec<Vec<u64>>,
+    pub kmers: Vec<Vec<K>>,
+    /// Group hierarchy from fine to coarse, empty if classes are not grouped
+    pub groups: Vec<GroupLevel<K>>,
+    /// Which kmers of a sequence are looked up
+    pub sampling: Sampling,
+    /// Filled in when loading, stored separately from the kmers
+    #[serde(skip)]
+    pub header: DbHeader,
 }
 
-impl SingletonKmers {
+impl<K: Clone> SingletonKmers<K> {
     #[inline]
     pub fn n(&self) -> usize {
         self.fasta_files.len()
     }
+
+    /// Copy everything but the kmers
+    pub fn metadata(&self) -> SingletonKmers<K> {
+        SingletonKmers {
+            kmer_size: self.kmer_size,
+            fasta_files: self.fasta_files.clone(),
+            kmers: Vec::new(),
+            groups: self.groups.clone(),
+            sampling: self.sampling,
+            header: self.header.clone(),
+        }
+    }
+}
+
+/// Kmers found in more than one class, kept next to a database so that classes
+/// can be added or removed without a full rebuild. Each kmer is listed once for
+/// every class it occurs in, sorted by kmer.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SharedKmers<K = u64> {
+    pub fasta_files: Vec<String>,
+    pub kmers: Vec<K>,
+    pub classes: Vec<u16>,
+}
+
+/// Pseudo-random DNA sequence of `len` bases
+#[cfg(test)]
+pub(crate) fn random_seq(seed: u64, len: usize) -> String {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        })
+        .collect()
+}
+
+/// Database of classes A to C and groups at two levels, each holding the
+/// sorted kmers of a pseudo-random sequence
+#[cfg(test)]
+pub(crate) fn sample_db<K: crate::kmer::Kmer>(kmer_size: u8) -> SingletonKmers<K> {
+    let kmers = |seed| {
+        let mut kmers = Vec::new();
+        K::for_each_canonical(random_seq(seed, 300).as_bytes(), kmer_size, |_, kmer| {
+            kmers.push(kmer)
+        });
+        kmers.sort_unstable();
+        kmers.dedup();
+        kmers
+    };
+    let level = |name: &str, groups: &[&str], members: Vec<usize>, seeds: &[u64]| GroupLevel {
+        name: name.to_string(),
+        groups: groups.iter().map(|x| x.to_string()).collect(),
+        members,
+        kmers: seeds.iter().map(|&seed| kmers(seed)).collect(),
+    };
+    SingletonKmers {
+        kmer_size,
+        fasta_files: vec!["A".to_string(), "B".to_string(), "C".to_string()],
+        kmers: vec![kmers(1), kmers(2), kmers(3)],
+        groups: vec![
+            level("hom", &["H1", "H2"], vec![0, 0, 1], &[4, 5]),
+            level("sub", &["S1"], vec![0, 0, 0], &[6]),
+        ],
+        sampling: Sampling::All,
+        header: DbHeader {
+            kmer_size,
+            canonical: true,
+            params: vec![("kmer_size".to_string(), kmer_size.to_string())],
+            ..Default::default()
+        },
+    }
+}
+
+/// Block of one class along a read
+pub struct Segment {
+    pub class: usize,
+    pub start: usize,
+    pub end: usize,
+    pub kmers: usize,
+}
+
+/// Unique kmers at every position of `ranges` of (start, end, class)
+#[cfg(test)]
+pub(crate) fn hits(ranges: &[(usize, usize, usize)]) -> Vec<(usize, usize)> {
+    ranges
+        .iter()
+        .flat_map(|&(start, end, class)| (start..end).map(move |pos| (pos, class)))
+        .collect()
+}
+
+/// Format segments as `A:0-12000,B:12100-30000`, or `-` if there are none
+pub fn format_segments(segments: &[Segment], class_names: &[String]) -> String {
+    if segments.is_empty() {
+        return "-".to_string();
+    }
+    segments
+        .iter()
+        .map(|x| format!("{}:{}-{}", class_names[x.class], x.start, x.end))
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
 pub struct ClassifyResults {
@@ -33,7 +181,10 @@ pub struct ClassifyResults {
 }
 
 impl ClassifyResults {
-    pub fn tag(&self, fasta_files: &Vec<String>) -> String {
+    /// Tag the read with its best two classes and their percentages of the
+    /// unique kmers, e.g. `A,B:88,11`, followed by any other classes with at
+    /// least `min_class_score` percent if given, e.g. `A,B,C:60,25,12`
+    pub fn tag(&self, fasta_files: &[String], min_class_score: Option<i32>) -> String {
         let mut best_count = 0;
         let mut best_index = 0;
         let mut second_best_count = 0;
@@ -53,14 +204,39 @@ impl ClassifyResults {
         }
         let half = total / 2;
         if total == 0 {
-            "Unclassified:0,0".to_string()
-        } else if best_count + second_best_count > half {
+            return "Unclassified:0,0".to_string();
+        }
+        let mut others = (0..self.counts.len())
+            .filter(|&i| i != best_index && i != second_best_index)
+            .filter(|&i| {
+                min_class_score
+                    .is_some_and(|x| self.counts[i] > 0 && self.counts[i] * 100 / total >= x)
+            })
+            .collect::<Vec<_>>();
+        others.sort_by_key(|&i| std::cmp::Reverse(self.counts[i]));
+        let others_count: i32 = others.iter().map(|&i| self.counts[i]).sum();
+        if best_count + second_best_count > half
+            || (!others.is_empty() && best_count + second_best_count + others_count > half)
+        {
+            let classes = [
+                (best_index, best_count),
+                (second_best_index, second_best_count),
+            ]
+            .into_iter()
+            .chain(others.into_iter().map(|i| (i, self.counts[i])))
+            .collect::<Vec<_>>();
             format!(
-                "{},{}:{},{}",
-                fasta_files[best_index],
-                fasta_files[second_best_index],
-                best_count * 100 / total,
-                second_best_count * 100 / total
+                "{}:{}",
+                classes
+                    .iter()
+                    .map(|&(i, _)| fasta_files[i].as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                classes
+                    .iter()
+                    .map(|&(_, count)| (count * 100 / total).to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
             )
         } else {
             format!(
@@ -70,10 +246,75 @@ impl ClassifyResults {
             )
         }
     }
+
+    /// Log10 likelihood ratio of the read being chimeric over it coming from
+    /// its best class alone, where the kmers from other classes are false hits.
+    /// Each of the `n_kmers` kmers looked up in the read is a false hit with
+    /// probability `error_prob` times `density`, the fraction of the reference
+    /// kmers that are unique in the database, so longer reads and denser
+    /// databases give more confident scores. Without the density, the read's
+    /// own unique kmers stand in for its kmers, each a false hit with
+    /// probability `error_prob`.
+    pub fn lod(&self, error_prob: f64, density: Option<f64>, n_kmers: usize) -> f64 {
+        let hits = self.counts.iter().sum::<i32>() as f64;
+        let best = self.counts.iter().copied().max().unwrap_or(0) as f64;
+        let k = hits - best;
+        let (n, q) = match density {
+            Some(density) => (n_kmers as f64, error_prob * density),
+            None => (hits, error_prob),
+        };
+        if k == 0.0 || k / n <= q {
+            return 0.0;
+        }
+        let p = k / n;
+        k * (p / q).log10() + (n - k) * ((1.0 - p) / (1.0 - q)).log10()
+    }
+}
+
+/// Open a FASTA/FASTQ file, or the standard input for `-`. Compression with
+/// gzip, bzip2, xz or zstd is detected from the magic bytes.
+pub fn open_fastx(file_path: &str) -> Result<Box<dyn FastxReader>, ParseError> {
+    let reader: Box<dyn Read + Send> = if file_path == STDIN {
+        Box::new(std::io::stdin())
+    } else {
+        Box::new(fs::File::open(file_path)?)
+    };
+    parse_fastx(reader)
+}
+
+/// Parse FASTA/FASTQ records from a reader, detecting the compression
+fn parse_fastx(mut reader: Box<dyn Read + Send>) -> Result<Box<dyn FastxReader>, ParseError> {
+    // Peek at the magic bytes, which a pipe may deliver in several reads
+    let mut magic = [0u8; 4];
+    let mut n = 0;
+    while n < magic.len() {
+        match reader.read(&mut magic[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    let reader = Cursor::new(magic[..n].to_vec()).chain(reader);
+    if magic == ZSTD_MAGIC {
+        parse_fastx_reader(zstd::stream::read::Decoder::new(reader)?)
+    } else {
+        parse_fastx_reader(reader)
+    }
 }
 
-/// Get basename
+/// Check that the standard input is read at most once
+pub fn assert_stdin_once(file_paths: &[String]) {
+    assert!(
+        file_paths.iter().filter(|x| *x == STDIN).count() <= 1,
+        "standard input `{}` can only be given once",
+        STDIN
+    );
+}
+
+/// Get basename, or `stdin` for the standard input
 pub fn prefix(file_path: &str) -> String {
+    if file_path == STDIN {
+        return "stdin".to_string();
+    }
     Path::new(file_path)
         .file_name()
         .unwrap()
@@ -122,3 +363,89 @@ pub fn need_update(a: Vec<String>, b: Vec<String>, warn: bool) -> bool {
 
     should_update
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const FASTA: &[u8] = b">r1\nACGTACGT\n>r2\nGGCC\n";
+
+    /// IDs and sequences of all the records
+    fn records(mut reader: Box<dyn FastxReader>) -> Vec<(String, String)> {
+        let mut records = Vec::new();
+        while let Some(record) = reader.next() {
+            let record = record.unwrap();
+            records.push((
+                String::from_utf8_lossy(record.id()).to_string(),
+                String::from_utf8_lossy(&record.seq()).to_string(),
+            ));
+        }
+        records
+    }
+
+    fn expected() -> Vec<(String, String)> {
+        vec![
+            ("r1".to_string(), "ACGTACGT".to_string()),
+            ("r2".to_string(), "GGCC".to_string()),
+        ]
+    }
+
+    /// Open `bytes` written to a temporary file
+    fn open_bytes(bytes: &[u8]) -> Vec<(String, String)> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(bytes).unwrap();
+        records(open_fastx(file.path().to_str().unwrap()).unwrap())
+    }
+
+    /// Reader returning one byte at a time, like a slow pipe
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn test_open_fastx_plain() {
+        assert_eq!(open_bytes(FASTA), expected());
+    }
+
+    #[test]
+    fn test_open_fastx_gzip() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(FASTA).unwrap();
+        assert_eq!(open_bytes(&encoder.finish().unwrap()), expected());
+    }
+
+    #[test]
+    fn test_open_fastx_bzip2() {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(FASTA).unwrap();
+        assert_eq!(open_bytes(&encoder.finish().unwrap()), expected());
+    }
+
+    #[test]
+    fn test_open_fastx_xz() {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(FASTA).unwrap();
+        assert_eq!(open_bytes(&encoder.finish().unwrap()), expected());
+    }
+
+    #[test]
+    fn test_open_fastx_zstd() {
+        assert_eq!(open_bytes(&zstd::encode_all(FASTA, 0).unwrap()), expected());
+    }
+
+    #[test]
+    fn test_parse_fastx_pipe() {
+        // The magic bytes of a pipe may arrive in several reads
+        let zstd_bytes = zstd::encode_all(FASTA, 0).unwrap();
+        let reader = Box::new(Trickle(Cursor::new(zstd_bytes)));
+        assert_eq!(records(parse_fastx(reader).unwrap()), expected());
+        let reader = Box::new(Trickle(Cursor::new(FASTA.to_vec())));
+        assert_eq!(records(parse_fastx(reader).unwrap()), expected());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::models::open_fastx;
+
+use needletail::sequence::normalize;
+use needletail::Sequence;
+use rust_htslib::bam::{self, record::Aux, Read};
+
+/// Check if the reads are in SAM, BAM or CRAM format, from the file extension
+pub fn is_bam(reads_file: &str) -> bool {
+    let reads_file = reads_file.to_lowercase();
+    [".bam", ".cram", ".sam"]
+        .iter()
+        .any(|x| reads_file.ends_with(x))
+}
+
+/// Call `f` on the ID (first word of the name) and the normalized sequence of
+/// each read in a FASTA/FASTQ or unaligned BAM/CRAM file. BAM/CRAM reads with a
+/// read quality (`rq` tag) below `min_quality` are skipped; reads without the
+/// tag are kept. Secondary and supplementary alignments are skipped.
+pub fn for_each_read(reads_file: &str, min_quality: Option<f32>, mut f: impl FnMut(&str, &[u8])) {
+    if !is_bam(reads_file) {
+        let mut reader = open_fastx(reads_file).expect("valid reads file");
+        while let Some(record) = reader.next() {
+            let record = record.expect("valid record");
+            let seq = record.normalize(false);
+            // Get the first part of the ID
+            let id = String::from_utf8(record.id().to_vec())
+                .unwrap()
+                .split_whitespace()
+                .next()
+                .unwrap()
+                .to_string();
+            f(&id, &seq);
+        }
+        return;
+    }
+    let mut bam = bam::Reader::from_path(reads_file).expect("valid BAM/CRAM file");
+    let mut low_quality = 0;
+    for record in bam.records() {
+        let record = record.expect("valid record");
+        if record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        if let Some(min_quality) = min_quality {
+            let quality = match record.aux(b"rq") {
+                Ok(Aux::Float(rq)) => Some(rq),
+                Ok(Aux::Double(rq)) => Some(rq as f32),
+                _ => None,
+            };
+            if quality.is_some_and(|rq| rq < min_quality) {
+                low_quality += 1;
+                continue;
+            }
+        }
+        let mut seq = record.seq().as_bytes();
+        // Aligned reads on the reverse strand are stored reverse complemented
+        if record.is_reverse() {
+            seq = seq.reverse_complement();
+        }
+        let seq = normalize(&seq, false).unwrap_or(seq);
+        let id = String::from_utf8_lossy(record.qname());
+        f(&id, &seq);
+    }
+    if let Some(min_quality) = min_quality {
+        log::info!(
+            "Skipped {} reads with rq < {} in `{}`",
+            low_quality,
+            min_quality,
+            reads_file
+        );
+    }
+}
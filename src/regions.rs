@@ -109,11 +109,7 @@ fn process_bedfiles(bed_files: Vec<String>, chr_only: bool) -> HashMap<String, i
 
     let child_records = load_bed(child_bed);
     let parent1_records = load_bed(parent1_bed);
-    let parent2_records = if let Some(bed) = parent2_bed {
-        Some(load_bed(&bed))
-    } else {
-        None
-    };
+    let parent2_records = parent2_bed.map(|bed| load_bed(&bed));
 
     let mut regions: BTreeMap<String, Vec<(u32, u32, f64)>> = BTreeMap::new();
 
@@ -146,7 +142,7 @@ fn process_bedfiles(bed_files: Vec<String>, chr_only: bool) -> HashMap<String, i
 
         let chrom_selected: Vec<_> = data
             .iter()
-            .filter(|&&(_, _, depth)| depth >= 5.0 && depth <= 100.0)
+            .filter(|&&(_, _, depth)| (5.0..=100.0).contains(&depth))
             .map(|&(start, end, depth)| (chrom.clone(), start, end, format!("{}", depth.round())))
             .collect();
 
@@ -170,7 +166,7 @@ fn process_bedfiles(bed_files: Vec<String>, chr_only: bool) -> HashMap<String, i
         .from_path(&poi_tsv)
         .unwrap();
 
-    kf_writer.write_record(&["Chrom", "Regions"]).unwrap();
+    kf_writer.write_record(["Chrom", "Regions"]).unwrap();
 
     for (chrom, regions_str) in d {
         kf_writer.write_record(&[chrom, regions_str]).unwrap();
@@ -183,14 +179,13 @@ fn process_bedfiles(bed_files: Vec<String>, chr_only: bool) -> HashMap<String, i
 
     let mut merged = Vec::new();
 
-    for i in 0..selected.len() {
+    for (i, cur) in selected.iter().enumerate() {
         if i == 0 {
-            merged.push(selected[i].clone());
+            merged.push(cur.clone());
             continue;
         }
 
         let prev = merged.last_mut().unwrap();
-        let cur = &selected[i];
 
         if prev.0 == cur.0 && prev.2 + CHAIN_DISTANCE >= cur.1 {
             prev.2 = prev.2.max(cur.2);
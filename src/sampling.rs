@@ -0,0 +1,102 @@
+use crate::kmer::Kmer;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Which kmers of a sequence are looked up, shared by `build`, `classify` and
+/// `breakpoint` so that they all sample the same positions
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Sampling {
+    /// Every kmer
+    #[default]
+    All,
+    /// The kmer with the smallest hash in each window of `w` consecutive kmers
+    Minimizer { w: u8 },
+    /// Open syncmers, i.e. kmers whose smallest `s`-mer comes first
+    Syncmer { s: u8 },
+}
+
+impl fmt::Display for Sampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sampling::All => write!(f, "all kmers"),
+            Sampling::Minimizer { w } => write!(f, "minimizers (w={})", w),
+            Sampling::Syncmer { s } => write!(f, "open syncmers (s={})", s),
+        }
+    }
+}
+
+impl Sampling {
+    /// Check that the sampling parameters fit the kmer size
+    pub fn validate(&self, kmer_size: u8) {
+        match *self {
+            Sampling::All => {}
+            Sampling::Minimizer { w } => assert!(w > 0, "minimizer window must be at least 1"),
+            Sampling::Syncmer { s } => assert!(
+                s > 0 && s < kmer_size.min(32),
+                "syncmer size must be between 1 and {}",
+                kmer_size.min(32) - 1
+            ),
+        }
+    }
+
+    /// Expected fraction of the kmers of a sequence that are sampled
+    pub fn fraction(&self, kmer_size: u8) -> f64 {
+        match *self {
+            Sampling::All => 1.0,
+            Sampling::Minimizer { w } => (2.0 / (w as f64 + 1.0)).min(1.0),
+            Sampling::Syncmer { s } => 1.0 / (kmer_size - s + 1) as f64,
+        }
+    }
+}
+
+/// Check if the smallest `s`-mer of a kmer is its first one. The kmer is
+/// canonical, so both strands make the same choice.
+#[inline]
+fn is_open_syncmer<K: Kmer>(kmer: K, kmer_size: u8, s: u8) -> bool {
+    let n_smers = (kmer_size - s) as u32 + 1;
+    let mask = (1u64 << (2 * s as u32)) - 1;
+    let smer_hash = |i: u32| (kmer.high_bits(2 * (n_smers - 1 - i)) & mask).hash64();
+    let first = smer_hash(0);
+    (1..n_smers).all(|i| smer_hash(i) > first)
+}
+
+/// Call `f` on the position and value of each sampled canonical kmer of a
+/// normalized sequence. Kmers are ordered by hash, so that sampling does not
+/// favor poly-A kmers the way lexicographic order would.
+pub fn for_each_sampled_kmer<K: Kmer>(
+    seq: &[u8],
+    kmer_size: u8,
+    sampling: Sampling,
+    mut f: impl FnMut(usize, K),
+) {
+    match sampling {
+        Sampling::All => K::for_each_canonical(seq, kmer_size, f),
+        Sampling::Minimizer { w } => {
+            let w = w as usize;
+            // Candidates in the window by position, with increasing hashes
+            let mut window: VecDeque<(usize, u64, K)> = VecDeque::new();
+            let mut last = None;
+            K::for_each_canonical(seq, kmer_size, |pos, kmer| {
+                let hash = kmer.hash64();
+                while window.back().is_some_and(|&(_, h, _)| h >= hash) {
+                    window.pop_back();
+                }
+                window.push_back((pos, hash, kmer));
+                while window.front().is_some_and(|&(p, _, _)| p + w <= pos) {
+                    window.pop_front();
+                }
+                let &(min_pos, _, min_kmer) = window.front().unwrap();
+                if last != Some(min_pos) {
+                    f(min_pos, min_kmer);
+                    last = Some(min_pos);
+                }
+            });
+        }
+        Sampling::Syncmer { s } => K::for_each_canonical(seq, kmer_size, |pos, kmer| {
+            if is_open_syncmer(kmer, kmer_size, s) {
+                f(pos, kmer);
+            }
+        }),
+    }
+}
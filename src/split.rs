@@ -0,0 +1,299 @@
+use crate::db::{kmer_size_of, load_kmer_index};
+use crate::index::KmerIndex;
+use crate::kmer::{is_wide, Kmer};
+use crate::models::{assert_stdin_once, prefix, SingletonKmers};
+use crate::reads::for_each_read;
+use crate::sampling::for_each_sampled_kmer;
+
+use clap::Parser;
+use log;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Minimum number of kmers on each side of the breakpoint
+const KMER_THRESHOLD: usize = 30;
+
+const SUCCESS: &str = "SUCCESS";
+const FAIL: &str = "FAIL";
+const NOT_ENOUGH_KMERS: &str = "NOT_ENOUGH_KMERS";
+const MULTI_WAY: &str = "MULTI_WAY";
+
+#[derive(Parser, Debug)]
+pub struct SplitArgs {
+    /// Bincode file
+    pub bincode_file: String,
+    /// Chimeric reads written by `extract`, named `A_B_read`. Reads spanning
+    /// more classes, e.g. `A_B_C_read`, are reported as `MULTI_WAY`
+    #[clap(required = true)]
+    pub fasta_files: Vec<String>,
+    /// Minimum number of kmers of each class on its side of the breakpoint
+    #[clap(long, default_value_t = KMER_THRESHOLD)]
+    pub min_kmers: usize,
+}
+
+/// Split chimeric reads at the switch from one class to the other
+pub fn split(args: &SplitArgs) {
+    if is_wide(kmer_size_of(&args.bincode_file)) {
+        split_with::<u128>(args);
+    } else {
+        split_with::<u64>(args);
+    }
+}
+
+fn split_with<K: Kmer>(args: &SplitArgs) {
+    assert_stdin_once(&args.fasta_files);
+    let (singleton_kmers, kmer_to_file, _) = load_kmer_index::<K>(&args.bincode_file, true);
+    args.fasta_files.par_iter().for_each(|fasta_file| {
+        split_one(&singleton_kmers, &kmer_to_file, fasta_file, args.min_kmers);
+    });
+}
+
+/// Split the reads in one file, writing the subreads and the status of each read
+fn split_one<K: Kmer>(
+    singleton_kmers: &SingletonKmers<K>,
+    kmer_to_file: &KmerIndex<K>,
+    fasta_file: &str,
+    min_kmers: usize,
+) {
+    let file_prefix = prefix(fasta_file);
+    let output_file = file_prefix.to_string() + ".split.fasta";
+    let status_file = file_prefix.to_string() + ".split.tsv";
+    let mut writer = BufWriter::new(File::create(&output_file).unwrap());
+    let mut status_writer = BufWriter::new(File::create(&status_file).unwrap());
+    writeln!(
+        status_writer,
+        "ID\tStatus\tLeft\tRight\tBreakpoint\tLeftKmers\tRightKmers"
+    )
+    .unwrap();
+    let class_names = &singleton_kmers.fasta_files;
+
+    let kmer_size = singleton_kmers.kmer_size;
+    let sampling = singleton_kmers.sampling;
+    let mut counter = BTreeMap::new();
+    for_each_read(fasta_file, None, |read, seq| {
+        // Classes of the unique kmers along the read
+        let mut hits = Vec::new();
+        for_each_sampled_kmer(seq, kmer_size, sampling, |pos, kmer: K| {
+            if let Some(file_index) = kmer_to_file.get(kmer) {
+                hits.push((pos, file_index));
+            }
+        });
+        let status = match get_breakpoint(read, &hits, class_names, kmer_size, min_kmers) {
+            Ok(breakpoint) => {
+                let mid = breakpoint.mid;
+                let end = seq.len();
+                writeln!(
+                    writer,
+                    ">{}|{}|0-{}\n{}",
+                    breakpoint.read,
+                    breakpoint.left,
+                    mid,
+                    String::from_utf8_lossy(&seq[..mid])
+                )
+                .unwrap();
+                writeln!(
+                    writer,
+                    ">{}|{}|{}-{}\n{}",
+                    breakpoint.read,
+                    breakpoint.right,
+                    mid,
+                    end,
+                    String::from_utf8_lossy(&seq[mid..])
+                )
+                .unwrap();
+                writeln!(
+                    status_writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    breakpoint.read,
+                    SUCCESS,
+                    breakpoint.left,
+                    breakpoint.right,
+                    mid,
+                    breakpoint.left_kmers,
+                    breakpoint.right_kmers
+                )
+                .unwrap();
+                SUCCESS
+            }
+            Err(status) => {
+                writeln!(status_writer, "{}\t{}\t\t\t\t\t", read, status).unwrap();
+                status
+            }
+        };
+        *counter.entry(status).or_insert(0) += 1;
+    });
+    log::info!("Summary for `{}`: {:?}", fasta_file, counter);
+    log::info!("Split reads written to `{}`", output_file);
+    log::info!("Split status written to `{}`", status_file);
+}
+
+/// Switch from the left class to the right class along a read
+struct Breakpoint<'a> {
+    read: &'a str,
+    left: &'a str,
+    right: &'a str,
+    mid: usize,
+    left_kmers: usize,
+    right_kmers: usize,
+}
+
+/// Given a read named `A_B_read` and the classes of its unique kmers, find the
+/// split that maximizes the kmers of one class before it plus the kmers of the
+/// other class after it
+fn get_breakpoint<'a>(
+    name: &'a str,
+    hits: &[(usize, usize)],
+    class_names: &'a [String],
+    kmer_size: u8,
+    min_kmers: usize,
+) -> Result<Breakpoint<'a>, &'static str> {
+    let labels = parse_labels(name, class_names);
+    let (ra, rb, read) = match labels.as_slice() {
+        [.., read] if labels.len() > 3 => {
+            log::warn!("`{}` spans more than two classes. Skipped", read);
+            return Err(MULTI_WAY);
+        }
+        &[ra, rb, read] => (ra, rb, read),
+        _ => {
+            log::warn!("`{}` is not named `A_B_read`. Skipped", name);
+            return Err(FAIL);
+        }
+    };
+    let sids = hits
+        .iter()
+        .map(|&(_, file_index)| class_names[file_index].as_str())
+        .collect::<Vec<_>>();
+    let n = sids.len();
+    if n == 0 {
+        return Err(NOT_ENOUGH_KMERS);
+    }
+
+    // Kmers of each class up to and including i, and after i
+    let mut prefix_a = vec![0; n];
+    let mut prefix_b = vec![0; n];
+    let (mut a, mut b) = (0, 0);
+    for (i, &sid) in sids.iter().enumerate() {
+        a += (sid == ra) as usize;
+        b += (sid == rb) as usize;
+        prefix_a[i] = a;
+        prefix_b[i] = b;
+    }
+    let mut suffix_a = vec![0; n];
+    let mut suffix_b = vec![0; n];
+    for i in (1..n).rev() {
+        suffix_a[i - 1] = suffix_a[i] + (sids[i] == ra) as usize;
+        suffix_b[i - 1] = suffix_b[i] + (sids[i] == rb) as usize;
+    }
+    // First index of the best score, as numpy argmax
+    let argmax = |prefix: &[usize], suffix: &[usize]| {
+        (0..n).fold((0, 0), |(best, best_score), i| {
+            let score = prefix[i] + suffix[i];
+            if score > best_score || i == 0 {
+                (i, score)
+            } else {
+                (best, best_score)
+            }
+        })
+    };
+    let (ab_idx, ab_max) = argmax(&prefix_a, &suffix_b);
+    let (ba_idx, ba_max) = argmax(&prefix_b, &suffix_a);
+    let (idx, left, right, prefix, suffix) = if ab_max > ba_max {
+        (ab_idx, ra, rb, prefix_a, suffix_b)
+    } else {
+        (ba_idx, rb, ra, prefix_b, suffix_a)
+    };
+
+    let left_kmers = prefix[idx];
+    let right_kmers = suffix[idx];
+    if left_kmers < min_kmers || right_kmers < min_kmers {
+        return Err(NOT_ENOUGH_KMERS);
+    }
+    if idx == n - 1 {
+        return Err(FAIL);
+    }
+    let left_end = hits[idx].0 + kmer_size as usize;
+    let right_start = hits[idx + 1].0;
+    Ok(Breakpoint {
+        read,
+        left,
+        right,
+        mid: (left_end + right_start) / 2,
+        left_kmers,
+        right_kmers,
+    })
+}
+
+/// Split a read named `A_B_read` or `A_B_C_read` into its classes followed by
+/// the read ID. Known class names are matched first, as they may contain `_`
+fn parse_labels<'a>(name: &'a str, class_names: &[String]) -> Vec<&'a str> {
+    let mut labels = Vec::new();
+    let mut rest = name;
+    while let Some(class) = class_names
+        .iter()
+        .filter(|class| {
+            rest.len() > class.len()
+                && rest.starts_with(class.as_str())
+                && rest.as_bytes()[class.len()] == b'_'
+        })
+        .max_by_key(|class| class.len())
+    {
+        labels.push(&rest[..class.len()]);
+        rest = &rest[class.len() + 1..];
+    }
+    if labels.len() < 2 {
+        return name.splitn(3, '_').collect();
+    }
+    labels.push(rest);
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::hits;
+
+    fn class_names() -> Vec<String> {
+        vec!["A".to_string(), "B".to_string(), "C_1".to_string()]
+    }
+
+    #[test]
+    fn test_get_breakpoint() {
+        let class_names = class_names();
+        let hits = hits(&[(0, 100, 0), (100, 150, 1), (150, 160, 0), (160, 300, 1)]);
+        let breakpoint = get_breakpoint("A_B_r1", &hits, &class_names, 24, 10).unwrap();
+        assert_eq!(
+            (breakpoint.read, breakpoint.left, breakpoint.right),
+            ("r1", "A", "B")
+        );
+        assert_eq!(breakpoint.mid, (99 + 24 + 100) / 2);
+        assert_eq!((breakpoint.left_kmers, breakpoint.right_kmers), (100, 190));
+
+        // The order of the classes in the name does not matter
+        let breakpoint = get_breakpoint("B_A_r1", &hits, &class_names, 24, 10).unwrap();
+        assert_eq!((breakpoint.left, breakpoint.right), ("A", "B"));
+    }
+
+    #[test]
+    fn test_get_breakpoint_errors() {
+        let class_names = class_names();
+        let hits = hits(&[(0, 100, 0), (100, 105, 1)]);
+        let status = |name, hits: &[(usize, usize)]| {
+            get_breakpoint(name, hits, &class_names, 24, 10)
+                .map(|x| x.mid)
+                .unwrap_err()
+        };
+        assert_eq!(status("A_B_r1", &hits), NOT_ENOUGH_KMERS);
+        assert_eq!(status("A_B_r1", &[]), NOT_ENOUGH_KMERS);
+        assert_eq!(status("r1", &hits), FAIL);
+        assert_eq!(status("A_B_C_1_r1", &hits), MULTI_WAY);
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        let class_names = class_names();
+        assert_eq!(parse_labels("A_C_1_r_1", &class_names), ["A", "C_1", "r_1"]);
+        assert_eq!(parse_labels("X_Y_r_1", &class_names), ["X", "Y", "r_1"]);
+    }
+}